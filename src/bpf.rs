@@ -1,14 +1,31 @@
+use crate::hist::Histogram;
+use crate::procfs;
 use anyhow::{bail, Result};
-use libbpf_rs::PerfBufferBuilder;
+use libbpf_rs::MapFlags;
 use plain::Plain;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[path = "bpf/.output/mole.skel.rs"]
 mod mole;
 use mole::*;
 
-unsafe impl Plain for mole_bss_types::event {}
+unsafe impl Plain for mole_bss_types::wakeup_key {}
+unsafe impl Plain for mole_bss_types::hist {}
+unsafe impl Plain for mole_bss_types::offcpu {}
+unsafe impl Plain for mole_bss_types::stack_key {}
+unsafe impl Plain for mole_bss_types::wakeup_stack_key {}
+unsafe impl Plain for mole_bss_types::cpu_key {}
+unsafe impl Plain for mole_bss_types::chain_key {}
+unsafe impl Plain for mole_bss_types::chain {}
+unsafe impl Plain for mole_bss_types::chain_stats {}
+unsafe impl Plain for mole_bss_types::cpu_move_key {}
+unsafe impl Plain for mole_bss_types::preempt_key {}
+unsafe impl Plain for mole_bss_types::preemptor {}
+unsafe impl Plain for mole_bss_types::thread_life {}
+unsafe impl Plain for mole_bss_types::thread_exit {}
 
 fn bump_memlock_rlimit() -> Result<()> {
     let rlimit = libc::rlimit {
@@ -24,59 +41,824 @@ fn bump_memlock_rlimit() -> Result<()> {
 }
 
 pub type Wakeups = HashMap<(u64, u64), u64>; // (src_tgidpid, tgt_tgidpid) -> count
-pub type Slices = HashMap<i32, Vec<u64>>; // pid, duration
+pub type Hists = HashMap<i32, Histogram>; // pid -> histogram
+pub type CpuTime = HashMap<(i32, u32), u64>; // (pid, cpu) -> on-cpu us
+pub type CpuMoves = HashMap<(i32, u32, u32), u64>; // (pid, from cpu, to cpu) -> count
+pub type Preemptions = HashMap<(u64, u32), u64>; // (preemptor tgidpid, cpu) -> count
 
-fn handle_event(wakeups: &mut Wakeups, slices: &mut Slices, _cpu: i32, data: &[u8]) {
-    let mut event = mole_bss_types::event::default();
+// A thread as it was when it got on a cpu
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Task {
+    pub comm: String,
+    pub policy: u32, // SCHED_*
+}
+
+// Names of scheduling policies, indexed by SCHED_*
+const POLICIES: [&str; 8] = [
+    "normal", "fifo", "rr", "batch", "iso", "idle", "deadline", "ext",
+];
+
+pub fn policy_name(policy: u32) -> String {
+    match POLICIES.get(policy as usize) {
+        Some(name) => name.to_string(),
+        None => policy.to_string(),
+    }
+}
+
+// What else a cpu was busy with, us
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CpuShare {
+    pub other: u64,   // tasks of other processes
+    pub irq: u64,     // also in the time of the interrupted tasks
+    pub softirq: u64, // same
+    pub idle: u64,
+}
+
+impl CpuShare {
+    pub fn merge(&mut self, other: &CpuShare) {
+        self.other += other.other;
+        self.irq += other.irq;
+        self.softirq += other.softirq;
+        self.idle += other.idle;
+    }
+}
+
+// Task states at switch-out, in the order of OFFCPU_* in mole.h
+pub const OFFCPU_STATES: [&str; 4] = ["preempted", "sleep", "dsleep", "other"];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OffCpu {
+    pub count: [u64; 4],
+    pub time: [u64; 4], // us
+}
 
-    plain::copy_from_bytes(&mut event, data).expect("Data buffer was too short");
+impl OffCpu {
+    pub fn merge(&mut self, other: &OffCpu) {
+        for i in 0..OFFCPU_STATES.len() {
+            self.count[i] += other.count[i];
+            self.time[i] += other.time[i];
+        }
+    }
+}
+
+// Frame addresses, innermost first
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Stack {
+    pub kernel: Vec<u64>,
+    pub user: Vec<u64>,
+}
+
+// Hop i is the time from the (i + 1)-th thread of a chain being woken up to
+// it waking up the next one, or getting on a cpu for the last one
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChainStats {
+    pub count: u64,
+    pub hop_ns: Vec<u64>, // summed up
+}
 
-    if event.kind == 0 {
-        let wakeup = wakeups
-            .entry((event.src_tgidpid, event.tgt_tgidpid))
-            .or_insert(0);
-        *wakeup += 1;
-    } else if event.kind == 1 {
-        let vec = slices.entry(event.src_tgidpid as i32).or_insert(vec![]);
-        (*vec).push(event.tgt_tgidpid);
+impl ChainStats {
+    pub fn merge(&mut self, other: &ChainStats) {
+        self.count += other.count;
+        self.hop_ns
+            .resize(other.hop_ns.len().max(self.hop_ns.len()), 0);
+        for (a, b) in self.hop_ns.iter_mut().zip(&other.hop_ns) {
+            *a += b;
+        }
     }
 }
 
-fn handle_lost_events(cpu: i32, count: u64) {
-    eprintln!("Lost {} events on CPU {}", count, cpu);
+pub type Chains = HashMap<Vec<i32>, ChainStats>; // pids along the chain -> stats
+
+// A thread which was created or exited during the interval
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ThreadLife {
+    pub tgid: i32,
+    pub parent: Option<i32>, // None if created before the tracing started
+    pub comm: String,
+    pub born_ns: Option<u64>, // since the epoch
+    pub exited_ns: Option<u64>,
+    pub lifetime_us: Option<u64>,
+    pub on_cpu_us: Option<u64>, // at exit
 }
 
-pub fn read_events(tgid: i32, duration: Duration, verbose: bool) -> Result<(Wakeups, Slices)> {
-    let mut skel_builder = MoleSkelBuilder::default();
-    if verbose {
-        skel_builder.obj_builder.debug(true);
+impl ThreadLife {
+    // A thread created in one interval exits in a later one
+    pub fn merge(&mut self, other: &ThreadLife) {
+        self.parent = self.parent.or(other.parent);
+        self.born_ns = self.born_ns.or(other.born_ns);
+        self.exited_ns = self.exited_ns.or(other.exited_ns);
+        self.lifetime_us = self.lifetime_us.or(other.lifetime_us);
+        self.on_cpu_us = self.on_cpu_us.or(other.on_cpu_us);
+        if other.exited_ns.is_some() {
+            self.comm = other.comm.clone();
+        }
     }
+}
+
+pub type Lifecycle = HashMap<i32, ThreadLife>; // pid -> creation and exit
+
+// Counters of a thread at exit, in the units of procfs
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Exit {
+    pub tgid: i32,
+    pub comm: String,
+    pub utime: u64, // clock ticks
+    pub stime: u64,
+    pub vctxsw: u64,
+    pub ivctxsw: u64,
+    pub on_cpu: u64,          // us
+    pub waiting_for_cpu: u64, // us
+    pub slices: u64,
+}
+
+pub type Stacks = HashMap<(i32, Stack), u64>; // (pid, stack) -> off-cpu us
+pub type WakerStacks = HashMap<((u64, u64), Stack), u64>; // (wakeup edge, waker stack) -> count
 
-    bump_memlock_rlimit()?;
-    let mut open_skel = skel_builder.open()?;
-    open_skel.rodata().tgid = tgid;
+// Maps with tuple keys are stored as lists of (key, value) pairs
+mod pairs {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::HashMap;
+    use std::hash::Hash;
 
-    let mut skel = open_skel.load()?;
-    skel.attach()?;
+    pub fn serialize<K, V, S>(map: &HashMap<K, V>, s: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        s.collect_seq(map.iter())
+    }
 
-    let mut wakeups = Wakeups::new();
-    let mut slices = Slices::new();
+    pub fn deserialize<'de, K, V, D>(d: D) -> Result<HashMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
     {
-        let perf = PerfBufferBuilder::new(skel.maps_mut().events())
-            .sample_cb(|cpu: i32, data: &[u8]| {
-                handle_event(&mut wakeups, &mut slices, cpu, data);
-            })
-            .lost_cb(handle_lost_events)
-            .build()?;
-
-        let start = Instant::now();
-        loop {
-            perf.poll(Duration::from_millis(100))?;
-            if Instant::now() - start > duration {
-                break;
+        Ok(Vec::<(K, V)>::deserialize(d)?.into_iter().collect())
+    }
+}
+
+// Everything collected during one interval
+#[derive(Default, Serialize, Deserialize)]
+pub struct Interval {
+    #[serde(with = "pairs")]
+    pub wakeups: Wakeups,
+    pub slices: Hists, // on-cpu slice durations
+    pub runq: Hists,   // wakeup or preemption to run latencies
+    pub offcpu: HashMap<i32, OffCpu>,
+    #[serde(with = "pairs")]
+    pub offcpu_stacks: Stacks,
+    #[serde(with = "pairs")]
+    pub waker_stacks: WakerStacks,
+    #[serde(with = "pairs")]
+    pub cpus: CpuTime,
+    #[serde(with = "pairs")]
+    pub chains: Chains,
+    #[serde(with = "pairs")]
+    pub migrations: CpuMoves,
+    #[serde(with = "pairs")]
+    pub wakeup_cpus: CpuMoves, // from the cpu of the waker to where the wakee ran
+    pub cpu_shares: HashMap<u32, CpuShare>,
+    #[serde(with = "pairs")]
+    pub preemptions: Preemptions,
+    pub preemptors: HashMap<u64, Task>, // tgidpid -> task
+    pub lifecycle: Lifecycle,
+    pub exits: HashMap<i32, Exit>,
+}
+
+impl Interval {
+    pub fn merge(&mut self, other: &Interval) {
+        for (edge, count) in &other.wakeups {
+            *self.wakeups.entry(*edge).or_insert(0) += count;
+        }
+
+        merge_hists(&mut self.slices, &other.slices);
+        merge_hists(&mut self.runq, &other.runq);
+
+        for (pid, offcpu) in &other.offcpu {
+            self.offcpu.entry(*pid).or_default().merge(offcpu);
+        }
+
+        for (key, us) in &other.offcpu_stacks {
+            *self.offcpu_stacks.entry(key.clone()).or_insert(0) += us;
+        }
+
+        for (key, count) in &other.waker_stacks {
+            *self.waker_stacks.entry(key.clone()).or_insert(0) += count;
+        }
+
+        for (key, us) in &other.cpus {
+            *self.cpus.entry(*key).or_insert(0) += us;
+        }
+
+        for (pids, stats) in &other.chains {
+            self.chains.entry(pids.clone()).or_default().merge(stats);
+        }
+
+        for (key, count) in &other.migrations {
+            *self.migrations.entry(*key).or_insert(0) += count;
+        }
+
+        for (key, count) in &other.wakeup_cpus {
+            *self.wakeup_cpus.entry(*key).or_insert(0) += count;
+        }
+
+        for (cpu, share) in &other.cpu_shares {
+            self.cpu_shares.entry(*cpu).or_default().merge(share);
+        }
+
+        for (key, count) in &other.preemptions {
+            *self.preemptions.entry(*key).or_insert(0) += count;
+        }
+
+        for (tgidpid, task) in &other.preemptors {
+            self.preemptors.insert(*tgidpid, task.clone());
+        }
+
+        for (pid, life) in &other.lifecycle {
+            self.lifecycle.entry(*pid).or_default().merge(life);
+        }
+
+        for (pid, exit) in &other.exits {
+            self.exits.insert(*pid, exit.clone());
+        }
+    }
+}
+
+fn merge_hists(to: &mut Hists, from: &Hists) {
+    for (pid, hist) in from {
+        to.entry(*pid).or_insert_with(Histogram::new).merge(hist);
+    }
+}
+
+// Indexes in the dropped map, see mole.h
+const DROPPED_WAKEUPS: u32 = 0;
+const DROPPED_STACKS: u32 = 1;
+
+// Read and reset a map, a few updates racing with the lookup can get lost
+fn drain_map<F: FnMut(&[u8], &[u8])>(map: &mut libbpf_rs::Map, mut f: F) -> Result<()> {
+    let keys: Vec<_> = map.keys().collect();
+    for key in keys {
+        if let Some(data) = map.lookup(&key, MapFlags::ANY)? {
+            f(&key, &data);
+        }
+        map.delete(&key)?;
+    }
+
+    Ok(())
+}
+
+fn key_pid(key: &[u8]) -> i32 {
+    u32::from_ne_bytes(key[..4].try_into().unwrap()) as i32
+}
+
+fn drain_wakeups(map: &mut libbpf_rs::Map) -> Result<Wakeups> {
+    let mut ret = Wakeups::new();
+
+    drain_map(map, |key, data| {
+        let mut wakeup = mole_bss_types::wakeup_key::default();
+        plain::copy_from_bytes(&mut wakeup, key).expect("Data buffer was too short");
+
+        let count = u64::from_ne_bytes(data[..8].try_into().unwrap());
+        ret.insert((wakeup.src_tgidpid, wakeup.tgt_tgidpid), count);
+    })?;
+
+    Ok(ret)
+}
+
+fn drain_hists(map: &mut libbpf_rs::Map) -> Result<Hists> {
+    let mut ret = Hists::new();
+
+    drain_map(map, |key, data| {
+        let mut hist = mole_bss_types::hist::default();
+        plain::copy_from_bytes(&mut hist, data).expect("Data buffer was too short");
+
+        ret.insert(
+            key_pid(key),
+            Histogram::from_slots(&hist.slots, hist.sum, hist.max),
+        );
+    })?;
+
+    Ok(ret)
+}
+
+fn drain_offcpu(map: &mut libbpf_rs::Map) -> Result<HashMap<i32, OffCpu>> {
+    let mut ret = HashMap::new();
+
+    drain_map(map, |key, data| {
+        let mut offcpu = mole_bss_types::offcpu::default();
+        plain::copy_from_bytes(&mut offcpu, data).expect("Data buffer was too short");
+
+        ret.insert(
+            key_pid(key),
+            OffCpu {
+                count: offcpu.count,
+                time: offcpu.time,
+            },
+        );
+    })?;
+
+    Ok(ret)
+}
+
+//
+// Resolves stack ids. Ids stay in the map: identical stacks share an id, so
+// threads still off-cpu may hold the ones read here.
+//
+struct StackReader<'a> {
+    map: &'a mut libbpf_rs::Map,
+    cache: HashMap<i32, Vec<u64>>,
+}
+
+impl<'a> StackReader<'a> {
+    fn new(map: &'a mut libbpf_rs::Map) -> StackReader<'a> {
+        StackReader {
+            map,
+            cache: HashMap::new(),
+        }
+    }
+
+    fn read(&mut self, id: i32) -> Result<Vec<u64>> {
+        if id < 0 {
+            return Ok(vec![]);
+        }
+
+        if let Some(frames) = self.cache.get(&id) {
+            return Ok(frames.clone());
+        }
+
+        let mut frames = vec![];
+        if let Some(data) = self.map.lookup(&id.to_ne_bytes(), MapFlags::ANY)? {
+            for addr in data.chunks_exact(8) {
+                let addr = u64::from_ne_bytes(addr.try_into().unwrap());
+                if addr == 0 {
+                    break;
+                }
+                frames.push(addr);
+            }
+        }
+
+        self.cache.insert(id, frames.clone());
+        Ok(frames)
+    }
+}
+
+fn drain_cpu_time(map: &mut libbpf_rs::Map) -> Result<CpuTime> {
+    let mut ret = CpuTime::new();
+
+    drain_map(map, |key, data| {
+        let mut cpu_key = mole_bss_types::cpu_key::default();
+        plain::copy_from_bytes(&mut cpu_key, key).expect("Data buffer was too short");
+
+        let us = u64::from_ne_bytes(data[..8].try_into().unwrap());
+        ret.insert((cpu_key.pid as i32, cpu_key.cpu), us);
+    })?;
+
+    Ok(ret)
+}
+
+fn drain_cpu_moves(map: &mut libbpf_rs::Map) -> Result<CpuMoves> {
+    let mut ret = CpuMoves::new();
+
+    drain_map(map, |key, data| {
+        let mut move_key = mole_bss_types::cpu_move_key::default();
+        plain::copy_from_bytes(&mut move_key, key).expect("Data buffer was too short");
+
+        let count = u64::from_ne_bytes(data[..8].try_into().unwrap());
+        ret.insert((move_key.pid as i32, move_key.from, move_key.to), count);
+    })?;
+
+    Ok(ret)
+}
+
+// Task names are NUL terminated unless they take the whole buffer
+fn comm_string(comm: &[i8]) -> String {
+    let bytes: Vec<u8> = comm
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect();
+
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+fn drain_preemptions(map: &mut libbpf_rs::Map) -> Result<(Preemptions, HashMap<u64, Task>)> {
+    let mut counts = Preemptions::new();
+    let mut tasks = HashMap::new();
+
+    drain_map(map, |key, data| {
+        let mut preempt_key = mole_bss_types::preempt_key::default();
+        let mut preemptor = mole_bss_types::preemptor::default();
+        plain::copy_from_bytes(&mut preempt_key, key).expect("Data buffer was too short");
+        plain::copy_from_bytes(&mut preemptor, data).expect("Data buffer was too short");
+
+        *counts
+            .entry((preempt_key.tgidpid, preempt_key.cpu))
+            .or_insert(0) += preemptor.count;
+        tasks.insert(
+            preempt_key.tgidpid,
+            Task {
+                comm: comm_string(&preemptor.comm),
+                policy: preemptor.policy,
+            },
+        );
+    })?;
+
+    Ok((counts, tasks))
+}
+
+//
+// Time of other processes comes from BPF, interrupts and idle from the
+// per-cpu lines of /proc/stat, which are in clock ticks.
+//
+fn cpu_shares(
+    map: &mut libbpf_rs::Map,
+    prev: &HashMap<u32, procfs::StatData>,
+    curr: &HashMap<u32, procfs::StatData>,
+) -> Result<HashMap<u32, CpuShare>> {
+    let mut ret: HashMap<u32, CpuShare> = HashMap::new();
+    let tick_us = 1_000_000 / unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+
+    drain_map(map, |key, data| {
+        let cpu = u32::from_ne_bytes(key[..4].try_into().unwrap());
+        ret.entry(cpu).or_default().other = u64::from_ne_bytes(data[..8].try_into().unwrap());
+    })?;
+
+    for (cpu, c) in curr {
+        let p = match prev.get(cpu) {
+            Some(p) => p,
+            None => continue, // went online during the interval
+        };
+        let share = ret.entry(*cpu).or_default();
+        share.irq = c.irq.saturating_sub(p.irq) * tick_us;
+        share.softirq = c.softirq.saturating_sub(p.softirq) * tick_us;
+        share.idle = c.idle.saturating_sub(p.idle) * tick_us;
+    }
+
+    Ok(ret)
+}
+
+// Same clock as bpf_ktime_get_ns()
+fn monotonic_ns() -> u64 {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+// Wall clock time of a monotonic one, ns since the epoch
+fn epoch_ns(ns: u64, now_ns: u64) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+
+    now.saturating_sub(now_ns.saturating_sub(ns))
+}
+
+//
+// Threads created since the previous drain and threads which exited. Live
+// threads are left in the map, so their creation time is known on exit.
+//
+fn drain_lifecycle(map: &mut libbpf_rs::Map, since: u64, until: u64) -> Result<Lifecycle> {
+    let mut ret = Lifecycle::new();
+
+    let keys: Vec<_> = map.keys().collect();
+    for key in keys {
+        let data = match map.lookup(&key, MapFlags::ANY)? {
+            Some(data) => data,
+            None => continue,
+        };
+        let mut life = mole_bss_types::thread_life::default();
+        plain::copy_from_bytes(&mut life, &data).expect("Data buffer was too short");
+
+        let born = life.born != 0 && life.born >= since && life.born < until;
+        let exited = life.exited != 0;
+        if !born && !exited {
+            continue;
+        }
+        if exited {
+            map.delete(&key)?;
+        }
+
+        let mut thread = ThreadLife {
+            tgid: life.tgid as i32,
+            comm: comm_string(&life.comm),
+            ..Default::default()
+        };
+        if life.born != 0 {
+            thread.parent = Some(life.parent as i32);
+            thread.born_ns = Some(epoch_ns(life.born, until));
+        }
+        if exited {
+            thread.exited_ns = Some(epoch_ns(life.exited, until));
+            thread.on_cpu_us = Some(life.on_cpu / 1000);
+            if life.born != 0 {
+                thread.lifetime_us = Some((life.exited - life.born) / 1000);
             }
         }
+
+        // a pid reused within the interval is shown for one of its threads only
+        ret.insert(key_pid(&key), thread);
+    }
+
+    Ok(ret)
+}
+
+fn drain_exits(map: &mut libbpf_rs::Map) -> Result<HashMap<i32, Exit>> {
+    let mut ret = HashMap::new();
+    let ns_per_tick = 1_000_000_000 / unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+
+    drain_map(map, |key, data| {
+        let mut exit = mole_bss_types::thread_exit::default();
+        plain::copy_from_bytes(&mut exit, data).expect("Data buffer was too short");
+
+        ret.insert(
+            key_pid(key),
+            Exit {
+                tgid: exit.tgid as i32,
+                comm: comm_string(&exit.comm),
+                utime: exit.utime / ns_per_tick,
+                stime: exit.stime / ns_per_tick,
+                vctxsw: exit.nvcsw,
+                ivctxsw: exit.nivcsw,
+                on_cpu: exit.on_cpu / 1000,
+                waiting_for_cpu: exit.run_delay / 1000,
+                slices: exit.pcount,
+            },
+        );
+    })?;
+
+    Ok(ret)
+}
+
+// Chains of threads which haven't been woken up since the given time
+fn expire_chains(map: &mut libbpf_rs::Map, before: u64) -> Result<()> {
+    let keys: Vec<_> = map.keys().collect();
+    for key in keys {
+        let data = match map.lookup(&key, MapFlags::ANY)? {
+            Some(data) => data,
+            None => continue,
+        };
+        let mut chain = mole_bss_types::chain::default();
+        plain::copy_from_bytes(&mut chain, &data).expect("Data buffer was too short");
+
+        let last = (chain.len as usize).clamp(1, chain.ts.len()) - 1;
+        if chain.ts[last] < before {
+            map.delete(&key)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn drain_chains(map: &mut libbpf_rs::Map) -> Result<Chains> {
+    let mut ret = Chains::new();
+
+    drain_map(map, |key, data| {
+        let mut chain = mole_bss_types::chain_key::default();
+        let mut stats = mole_bss_types::chain_stats::default();
+        plain::copy_from_bytes(&mut chain, key).expect("Data buffer was too short");
+        plain::copy_from_bytes(&mut stats, data).expect("Data buffer was too short");
+
+        let len = chain.len as usize;
+        ret.insert(
+            chain.pids[..len].iter().map(|pid| *pid as i32).collect(),
+            ChainStats {
+                count: stats.count,
+                hop_ns: stats.hop_ns[..len - 1].to_vec(),
+            },
+        );
+    })?;
+
+    Ok(ret)
+}
+
+fn drain_stack_keys(map: &mut libbpf_rs::Map) -> Result<Vec<(mole_bss_types::stack_key, u64)>> {
+    let mut ret = vec![];
+
+    drain_map(map, |key, data| {
+        let mut stack_key = mole_bss_types::stack_key::default();
+        plain::copy_from_bytes(&mut stack_key, key).expect("Data buffer was too short");
+
+        ret.push((stack_key, u64::from_ne_bytes(data[..8].try_into().unwrap())));
+    })?;
+
+    Ok(ret)
+}
+
+fn drain_wakeup_stack_keys(
+    map: &mut libbpf_rs::Map,
+) -> Result<Vec<(mole_bss_types::wakeup_stack_key, u64)>> {
+    let mut ret = vec![];
+
+    drain_map(map, |key, data| {
+        let mut stack_key = mole_bss_types::wakeup_stack_key::default();
+        plain::copy_from_bytes(&mut stack_key, key).expect("Data buffer was too short");
+
+        ret.push((stack_key, u64::from_ne_bytes(data[..8].try_into().unwrap())));
+    })?;
+
+    Ok(ret)
+}
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub pids: Vec<i32>,      // tgids to profile
+    pub cgroup: Option<u64>, // and everything in the cgroup v2 with this id
+    pub cgroup_level: u32,   // at this depth under the root of the hierarchy
+    pub offcpu_stacks: bool,
+    pub waker_stacks: bool,
+    pub user_stacks: bool,
+    pub chains: bool,
+    pub placement: bool,
+    pub cpu_share: bool,
+    pub preemptors: bool,
+    pub lifecycle: bool,
+}
+
+//
+// Keeps the BPF programs attached for the whole run, so nothing is lost
+// between intervals. The data is accumulated in BPF maps until drain()
+// is called.
+//
+pub struct Collector {
+    skel: MoleSkel<'static>,
+    dropped_wakeups: u64,
+    dropped_stacks: u64,
+    cpu_stats: Option<HashMap<u32, procfs::StatData>>, // at the previous drain
+    drained_ns: u64,
+    chains: bool,
+}
+
+impl Collector {
+    pub fn new(opts: &Options, verbose: bool) -> Result<Collector> {
+        let mut skel_builder = MoleSkelBuilder::default();
+        if verbose {
+            skel_builder.obj_builder.debug(true);
+        }
+
+        bump_memlock_rlimit()?;
+        let mut open_skel = skel_builder.open()?;
+        open_skel.rodata().target_cgroup = opts.cgroup.unwrap_or(0);
+        open_skel.rodata().target_cgroup_level = opts.cgroup_level;
+        open_skel.rodata().want_offcpu_stacks = opts.offcpu_stacks;
+        open_skel.rodata().want_waker_stacks = opts.waker_stacks;
+        open_skel.rodata().want_user_stacks = opts.user_stacks;
+        open_skel.rodata().want_chains = opts.chains;
+        open_skel.rodata().want_placement = opts.placement;
+        open_skel.rodata().want_cpu_share = opts.cpu_share;
+        open_skel.rodata().want_preemptors = opts.preemptors;
+        open_skel.rodata().want_lifecycle = opts.lifecycle;
+
+        let mut skel = open_skel.load()?;
+        for pid in &opts.pids {
+            skel.maps_mut()
+                .targets()
+                .update(&pid.to_ne_bytes(), &[1], MapFlags::ANY)?;
+        }
+        skel.attach()?;
+
+        Ok(Collector {
+            skel,
+            dropped_wakeups: 0,
+            dropped_stacks: 0,
+            cpu_stats: if opts.cpu_share {
+                Some(procfs::read_cpu_stats())
+            } else {
+                None
+            },
+            drained_ns: monotonic_ns(),
+            chains: opts.chains,
+        })
+    }
+
+    pub fn poll(&self, duration: Duration) -> Result<()> {
+        std::thread::sleep(duration);
+
+        Ok(())
+    }
+
+    fn read_dropped(&self, idx: u32) -> Result<u64> {
+        let data = self
+            .skel
+            .maps()
+            .dropped()
+            .lookup(&idx.to_ne_bytes(), MapFlags::ANY)?;
+
+        Ok(data.map_or(0, |d| u64::from_ne_bytes(d[..8].try_into().unwrap())))
+    }
+
+    // Returns everything collected since the previous call
+    pub fn drain(&mut self) -> Result<Interval> {
+        let mut maps = self.skel.maps_mut();
+        let mut data = Interval {
+            wakeups: drain_wakeups(maps.wakeups())?,
+            slices: drain_hists(maps.slices())?,
+            runq: drain_hists(maps.runq())?,
+            offcpu: drain_offcpu(maps.offcpu())?,
+            offcpu_stacks: Stacks::new(),
+            waker_stacks: WakerStacks::new(),
+            cpus: drain_cpu_time(maps.cpu_time())?,
+            chains: drain_chains(maps.chain_stats())?,
+            migrations: drain_cpu_moves(maps.migrations())?,
+            wakeup_cpus: drain_cpu_moves(maps.wakeup_cpus())?,
+            cpu_shares: HashMap::new(),
+            preemptions: Preemptions::new(),
+            preemptors: HashMap::new(),
+            lifecycle: Lifecycle::new(),
+            exits: drain_exits(maps.exits())?,
+        };
+
+        let now = monotonic_ns();
+        data.lifecycle = drain_lifecycle(maps.lifecycle(), self.drained_ns, now)?;
+        if self.chains {
+            // chains don't span intervals
+            expire_chains(maps.chains(), self.drained_ns)?;
+        }
+        self.drained_ns = now;
+
+        let (preemptions, preemptors) = drain_preemptions(maps.preemptions())?;
+        data.preemptions = preemptions;
+        data.preemptors = preemptors;
+
+        if let Some(prev) = &mut self.cpu_stats {
+            let curr = procfs::read_cpu_stats();
+            data.cpu_shares = cpu_shares(maps.cpu_other(), prev, &curr)?;
+            *prev = curr;
+        }
+
+        let offcpu_stacks = drain_stack_keys(maps.offcpu_stacks())?;
+        let waker_stacks = drain_wakeup_stack_keys(maps.wakeup_stacks())?;
+        let mut stacks = StackReader::new(maps.stacks());
+        for (key, us) in offcpu_stacks {
+            let stack = Stack {
+                kernel: stacks.read(key.kern_stack)?,
+                user: stacks.read(key.user_stack)?,
+            };
+            *data
+                .offcpu_stacks
+                .entry((key.pid as i32, stack))
+                .or_insert(0) += us;
+        }
+        for (key, count) in waker_stacks {
+            let stack = Stack {
+                kernel: stacks.read(key.kern_stack)?,
+                user: stacks.read(key.user_stack)?,
+            };
+            *data
+                .waker_stacks
+                .entry(((key.src_tgidpid, key.tgt_tgidpid), stack))
+                .or_insert(0) += count;
+        }
+
+        let dropped = self.read_dropped(DROPPED_WAKEUPS)?;
+        if dropped > self.dropped_wakeups {
+            eprintln!(
+                "Dropped {} wakeups, the map is full",
+                dropped - self.dropped_wakeups
+            );
+            self.dropped_wakeups = dropped;
+        }
+
+        let dropped = self.read_dropped(DROPPED_STACKS)?;
+        if dropped > self.dropped_stacks {
+            eprintln!(
+                "Dropped {} stacks, their ids are taken in the stack map",
+                dropped - self.dropped_stacks
+            );
+            self.dropped_stacks = dropped;
+        }
+
+        Ok(data)
     }
+}
+
+#[test]
+fn interval_roundtrip() {
+    let mut data = Interval::default();
+    data.wakeups.insert((1 << 32 | 1, 2 << 32 | 2), 3);
+    data.slices
+        .insert(1, Histogram::from_slots(&[0, 1, 2], 5, 2));
+    data.waker_stacks.insert(
+        (
+            (1, 2),
+            Stack {
+                kernel: vec![0xffff],
+                user: vec![],
+            },
+        ),
+        4,
+    );
+
+    let json = serde_json::to_string(&data).unwrap();
+    let copy: Interval = serde_json::from_str(&json).unwrap();
 
-    Ok((wakeups, slices))
+    assert_eq!(copy.wakeups, data.wakeups);
+    assert_eq!(copy.slices[&1].slots, vec![0, 1, 2]);
+    assert_eq!(copy.slices[&1].sum, 5);
+    assert_eq!(copy.waker_stacks, data.waker_stacks);
 }
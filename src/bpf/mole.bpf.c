@@ -5,16 +5,77 @@
 #include <bpf/bpf_tracing.h>
 #include <bpf/bpf_core_read.h>
 
-const volatile pid_t tgid = 0;
+#define TASK_RUNNING 0
+#define TASK_INTERRUPTIBLE 1
+#define TASK_UNINTERRUPTIBLE 2
+#define EEXIST 17
 
-// Dummy instance to get skeleton to generate definition for `struct event`
-struct event _event = {0};
+/* cgroup v2 id (inode number) to profile, with its descendants, on top of the targets map */
+const volatile u64 target_cgroup = 0;
+const volatile u32 target_cgroup_level = 0;
+const volatile bool want_offcpu_stacks = false;
+const volatile bool want_waker_stacks = false;
+const volatile bool want_user_stacks = false;
+const volatile bool want_chains = false;
+const volatile bool want_placement = false;
+const volatile bool want_cpu_share = false;
+const volatile bool want_preemptors = false;
+const volatile bool want_lifecycle = false;
+
+// Dummy instances to get skeleton to generate definitions of the structs used from Rust
+struct wakeup_key _wakeup_key = {0};
+struct stack_key _stack_key = {0};
+struct wakeup_stack_key _wakeup_stack_key = {0};
+struct cpu_key _cpu_key = {0};
+struct chain_key _chain_key = {0};
+struct chain _chain = {0};
+struct cpu_move_key _cpu_move_key = {0};
+struct preempt_key _preempt_key = {0};
+struct preemptor _preemptor = {0};
+struct thread_life _thread_life = {0};
+struct thread_exit _thread_exit = {0};
+struct chain_stats zero_chain_stats = {0};
+
+// Initial value for new histograms, also gets `struct hist` into the skeleton
+struct hist zero_hist = {0};
+
+// Same for `struct offcpu`
+struct offcpu zero_offcpu = {0};
+
+struct switch_out {
+	u64 ts;
+	u32 state;
+	s32 kern_stack;
+	s32 user_stack;
+};
 
 // Kernel 5.14 changed the state field to __state
 struct task_struct___pre_5_14 {
 	long int state;
 };
 
+// Kernel 5.16 moved the cpu field out of thread_info
+struct thread_info___pre_5_16 {
+	u32 cpu;
+};
+
+struct task_struct___pre_5_16 {
+	struct thread_info___pre_5_16 thread_info;
+};
+
+// Kernel 6.0 replaced ancestor_ids with pointers to the ancestors
+struct cgroup___6_0 {
+	struct cgroup *ancestors[0];
+};
+
+/* tgids of the processes to profile, filled by userspace */
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, MAX_TARGETS);
+	__type(key, u32);
+	__type(value, u8);
+} targets SEC(".maps");
+
 struct {
 	__uint(type, BPF_MAP_TYPE_HASH);
 	__uint(max_entries, 10240);
@@ -23,10 +84,199 @@ struct {
 } start SEC(".maps");
 
 struct {
-	__uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, u32);
+	__type(value, struct hist);
+} slices SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, u32);
+	__type(value, u64);
+} runnable SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, u32);
+	__type(value, struct hist);
+} runq SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, u32);
+	__type(value, struct switch_out);
+} switched_out SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, u32);
+	__type(value, struct offcpu);
+} offcpu SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, struct cpu_key);
+	__type(value, u64);
+} cpu_time SEC(".maps");
+
+/* cpu of the waker until the wakee gets on a cpu */
+struct {
+	__uint(type, BPF_MAP_TYPE_LRU_HASH);
+	__uint(max_entries, 10240);
+	__type(key, u32);
+	__type(value, u32);
+} waker_cpu SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, struct cpu_move_key);
+	__type(value, u64);
+} migrations SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, struct cpu_move_key);
+	__type(value, u64);
+} wakeup_cpus SEC(".maps");
+
+/* When the current task of every cpu got on it, ns */
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY);
+	__uint(max_entries, MAX_CPUS);
+	__type(key, u32);
+	__type(value, u64);
+} cpu_switch_ts SEC(".maps");
+
+/* Time taken by tasks of other processes, per cpu, us */
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, MAX_CPUS);
+	__type(key, u32);
+	__type(value, u64);
+} cpu_other SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, struct preempt_key);
+	__type(value, struct preemptor);
+} preemptions SEC(".maps");
+
+/* Threads created or exited, exited ones are removed by userspace */
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, struct life_key);
+	__type(value, struct thread_life);
+} lifecycle SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, u32);
+	__type(value, struct thread_exit);
+} exits SEC(".maps");
+
+/* The latest chain every woken up thread is on */
+struct {
+	__uint(type, BPF_MAP_TYPE_LRU_HASH);
+	__uint(max_entries, 10240);
+	__type(key, u32);
+	__type(value, struct chain);
+} chains SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, struct chain_key);
+	__type(value, struct chain_stats);
+} chain_stats SEC(".maps");
+
+/*
+ * Stacks are never deleted, as ids are shared by identical stacks and may
+ * be pending in switched_out. Once a bucket is taken by another stack,
+ * bpf_get_stackid() fails instead of reusing it, so no id ever changes.
+ * Such failures are counted in the dropped map.
+ */
+struct {
+	__uint(type, BPF_MAP_TYPE_STACK_TRACE);
+	__uint(max_entries, 65536);
 	__uint(key_size, sizeof(u32));
-	__uint(value_size, sizeof(u32));
-} events SEC(".maps");
+	__uint(value_size, MAX_STACK_DEPTH * sizeof(u64));
+} stacks SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, struct stack_key);
+	__type(value, u64);
+} offcpu_stacks SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, struct wakeup_key);
+	__type(value, u64);
+} wakeups SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_HASH);
+	__uint(max_entries, 10240);
+	__type(key, struct wakeup_stack_key);
+	__type(value, u64);
+} wakeup_stacks SEC(".maps");
+
+struct {
+	__uint(type, BPF_MAP_TYPE_ARRAY);
+	__uint(max_entries, NR_DROPPED);
+	__type(key, u32);
+	__type(value, u64);
+} dropped SEC(".maps");
+
+static __always_inline bool is_target(struct task_struct *p)
+{
+	u32 tgid = BPF_CORE_READ(p, tgid);
+
+	if (bpf_map_lookup_elem(&targets, &tgid))
+		return true;
+
+	if (!target_cgroup)
+		return false;
+
+	/* the task's cgroup or its ancestor at the level of the target */
+	struct cgroup *cgrp = BPF_CORE_READ(p, cgroups, dfl_cgrp);
+	struct cgroup___6_0 *cgrp_6_0 = (void *)cgrp;
+	struct cgroup *ancestor;
+	u64 id;
+
+	if (BPF_CORE_READ(cgrp, level) < target_cgroup_level)
+		return false;
+
+	if (bpf_core_field_exists(cgrp_6_0->ancestors)) {
+		bpf_core_read(&ancestor, sizeof(ancestor),
+			      &cgrp_6_0->ancestors[target_cgroup_level]);
+		return BPF_CORE_READ(ancestor, kn, id) == target_cgroup;
+	}
+
+	bpf_core_read(&id, sizeof(id), &cgrp->ancestor_ids[target_cgroup_level]);
+	return id == target_cgroup;
+}
+
+static __always_inline u32 task_cpu(struct task_struct *p)
+{
+	if (bpf_core_field_exists(p->cpu))
+		return BPF_CORE_READ(p, cpu);
+
+	return BPF_CORE_READ((struct task_struct___pre_5_16 *)p, thread_info.cpu);
+}
 
 unsigned long tgidpid(pid_t tgid, pid_t pid)
 {
@@ -38,21 +288,280 @@ unsigned long tgidpid(pid_t tgid, pid_t pid)
 	return ret;
 }
 
+static __always_inline u64 log2(u32 v)
+{
+	u32 shift, r;
+
+	r = (v > 0xFFFF) << 4; v >>= r;
+	shift = (v > 0xFF) << 3; v >>= shift; r |= shift;
+	shift = (v > 0xF) << 2; v >>= shift; r |= shift;
+	shift = (v > 0x3) << 1; v >>= shift; r |= shift;
+	r |= (v >> 1);
+
+	return r;
+}
+
+static __always_inline u64 log2l(u64 v)
+{
+	u32 hi = v >> 32;
+
+	if (hi)
+		return log2(hi) + 32;
+	else
+		return log2(v);
+}
+
+static __always_inline u32 hist_slot(u64 v)
+{
+	u64 l;
+
+	if (v < 4)
+		return v;
+
+	l = log2l(v);
+	return (l - 1) * 4 + ((v >> (l - 2)) & 3);
+}
+
+static __always_inline void hist_add(void *map, u32 pid, u64 v)
+{
+	u32 slot = hist_slot(v);
+	struct hist *h;
+
+	h = bpf_map_lookup_elem(map, &pid);
+	if (!h) {
+		bpf_map_update_elem(map, &pid, &zero_hist, BPF_NOEXIST);
+		h = bpf_map_lookup_elem(map, &pid);
+		if (!h)
+			return;
+	}
+
+	if (slot >= HIST_SLOTS)
+		slot = HIST_SLOTS - 1;
+
+	__sync_fetch_and_add(&h->slots[slot], 1);
+	__sync_fetch_and_add(&h->sum, v);
+	/* racy, but the slots still bound it if a value is lost */
+	if (v > h->max)
+		h->max = v;
+}
+
+static __always_inline void count_dropped(u32 idx)
+{
+	u64 *count = bpf_map_lookup_elem(&dropped, &idx);
+
+	if (count)
+		__sync_fetch_and_add(count, 1);
+}
+
+static __always_inline void count_wakeup(struct wakeup_key *key)
+{
+	u64 one = 1, *count;
+
+	count = bpf_map_lookup_elem(&wakeups, key);
+	if (count) {
+		__sync_fetch_and_add(count, 1);
+		return;
+	}
+
+	if (!bpf_map_update_elem(&wakeups, key, &one, BPF_NOEXIST))
+		return;
+
+	/* either another CPU has just added the key or the map is full */
+	count = bpf_map_lookup_elem(&wakeups, key);
+	if (count)
+		__sync_fetch_and_add(count, 1);
+	else
+		count_dropped(DROPPED_WAKEUPS);
+}
+
+static __always_inline int get_stackid(void *ctx, u64 flags)
+{
+	int id = bpf_get_stackid(ctx, &stacks, flags);
+
+	/* other errors mean there is no stack, e.g. user stack of a kthread */
+	if (id == -EEXIST)
+		count_dropped(DROPPED_STACKS);
+
+	return id;
+}
+
+/* Called in the context of the waker */
+static __always_inline void count_wakeup_stack(void *ctx,
+					       struct wakeup_key *wakeup)
+{
+	struct wakeup_stack_key key = {
+		.src_tgidpid = wakeup->src_tgidpid,
+		.tgt_tgidpid = wakeup->tgt_tgidpid,
+		.kern_stack = get_stackid(ctx, 0),
+		.user_stack = -1,
+	};
+	u64 one = 1, *count;
+
+	if (want_user_stacks)
+		key.user_stack = get_stackid(ctx, BPF_F_USER_STACK);
+
+	count = bpf_map_lookup_elem(&wakeup_stacks, &key);
+	if (count) {
+		__sync_fetch_and_add(count, 1);
+		return;
+	}
+
+	bpf_map_update_elem(&wakeup_stacks, &key, &one, BPF_NOEXIST);
+}
+
+static __always_inline void count_cpu_move(void *map, u32 pid, u32 from,
+					   u32 to)
+{
+	struct cpu_move_key key = {
+		.pid = pid,
+		.from = from,
+		.to = to,
+	};
+	u64 one = 1, *count;
+
+	count = bpf_map_lookup_elem(map, &key);
+	if (count) {
+		__sync_fetch_and_add(count, 1);
+		return;
+	}
+
+	bpf_map_update_elem(map, &key, &one, BPF_NOEXIST);
+}
+
+/* Called on the cpu the thread is switching in on */
+static __always_inline void trace_placement(u32 pid)
+{
+	u32 cpu = bpf_get_smp_processor_id();
+	u32 *from;
+
+	from = bpf_map_lookup_elem(&waker_cpu, &pid);
+	if (!from)
+		return;
+
+	count_cpu_move(&wakeup_cpus, pid, *from, cpu);
+	bpf_map_delete_elem(&waker_cpu, &pid);
+}
+
+/*
+ * The wakee continues the chain of the waker, keeping the last MAX_CHAIN
+ * threads. A thread which is already on the chain starts a new one, so
+ * ping-pongs don't make up chains.
+ */
+static __always_inline void extend_chain(u32 waker, u32 wakee)
+{
+	struct chain c = {}, *prev;
+	u64 now = bpf_ktime_get_ns();
+	u32 i, j, len = 0, skip = 0;
+
+	prev = bpf_map_lookup_elem(&chains, &waker);
+	if (prev) {
+		len = prev->len;
+		if (len > MAX_CHAIN)
+			len = MAX_CHAIN;
+
+		for (i = 0; i < MAX_CHAIN; i++) {
+			if (i < len && prev->pids[i] == wakee)
+				len = 0;
+		}
+	}
+
+	if (!prev || !len) {
+		c.pids[0] = waker;
+		c.ts[0] = now;
+		c.len = 1;
+	} else {
+		if (len == MAX_CHAIN)
+			skip = 1;
+
+		for (i = 0; i < MAX_CHAIN - 1; i++) {
+			j = i + skip;
+			if (j >= len || j >= MAX_CHAIN)
+				break;
+			c.pids[i] = prev->pids[j];
+			c.ts[i] = prev->ts[j];
+			c.len++;
+		}
+	}
+
+	for (i = 0; i < MAX_CHAIN; i++) {
+		if (i == c.len) {
+			c.pids[i] = wakee;
+			c.ts[i] = now;
+		}
+	}
+	c.len++;
+
+	bpf_map_update_elem(&chains, &wakee, &c, 0);
+}
+
+/* Accounts the chain once its last thread gets on a cpu */
+static __always_inline void finish_chain(u32 pid)
+{
+	struct chain_key key = {};
+	struct chain_stats *stats;
+	struct chain *c;
+	u64 now = bpf_ktime_get_ns();
+	u32 i;
+
+	c = bpf_map_lookup_elem(&chains, &pid);
+	if (!c || c->ran)
+		return;
+	c->ran = 1;
+
+	/* single wakeups are in the wakeups map already */
+	if (c->len < 3 || c->len > MAX_CHAIN)
+		return;
+
+	key.len = c->len;
+	for (i = 0; i < MAX_CHAIN; i++) {
+		if (i < c->len)
+			key.pids[i] = c->pids[i];
+	}
+
+	stats = bpf_map_lookup_elem(&chain_stats, &key);
+	if (!stats) {
+		bpf_map_update_elem(&chain_stats, &key, &zero_chain_stats,
+				    BPF_NOEXIST);
+		stats = bpf_map_lookup_elem(&chain_stats, &key);
+		if (!stats)
+			return;
+	}
+
+	__sync_fetch_and_add(&stats->count, 1);
+	for (i = 0; i < MAX_CHAIN - 1; i++) {
+		if (i + 2 < MAX_CHAIN && i + 2 < c->len)
+			__sync_fetch_and_add(&stats->hop_ns[i],
+					     c->ts[i + 2] - c->ts[i + 1]);
+		else if (i + 2 == c->len)
+			__sync_fetch_and_add(&stats->hop_ns[i],
+					     now - c->ts[i + 1]);
+	}
+}
+
 SEC("kprobe/try_to_wake_up")
 int BPF_KPROBE(mole_handle_try_to_wake_up, struct task_struct *p,
 	       unsigned int state, int wake_flags)
 {
 	struct task_struct *curr = bpf_get_current_task_btf();
-	struct event event = {};
+	struct wakeup_key key = {};
 	pid_t tgt_tgid = BPF_CORE_READ(p, tgid);
 
-	if (curr->tgid == tgid || tgt_tgid == tgid) {
-		event.kind = 0;
-		event.src_tgidpid = tgidpid(curr->tgid, curr->pid);
-		event.tgt_tgidpid = tgidpid(tgt_tgid, BPF_CORE_READ(p, pid));
+	if (is_target(curr) || is_target(p)) {
+		key.src_tgidpid = tgidpid(curr->tgid, curr->pid);
+		key.tgt_tgidpid = tgidpid(tgt_tgid, BPF_CORE_READ(p, pid));
+
+		count_wakeup(&key);
+		if (want_waker_stacks)
+			count_wakeup_stack(ctx, &key);
+		if (want_chains)
+			extend_chain(curr->pid, BPF_CORE_READ(p, pid));
+	}
+
+	if (want_placement && is_target(p)) {
+		u32 pid = BPF_CORE_READ(p, pid);
+		u32 cpu = bpf_get_smp_processor_id();
 
-		bpf_perf_event_output(ctx, &events, BPF_F_CURRENT_CPU, &event,
-				      sizeof(event));
+		bpf_map_update_elem(&waker_cpu, &pid, &cpu, 0);
 	}
 
 	return 0;
@@ -65,6 +574,149 @@ static __always_inline int trace_enqueue(u32 pid)
 	return 0;
 }
 
+static __always_inline void trace_runnable(u32 pid)
+{
+	u64 ts = bpf_ktime_get_ns();
+	bpf_map_update_elem(&runnable, &pid, &ts, 0);
+}
+
+static __always_inline void trace_run(u32 pid)
+{
+	u64 *tsp, delta_us;
+
+	tsp = bpf_map_lookup_elem(&runnable, &pid);
+	if (!tsp)
+		return;
+
+	delta_us = (bpf_ktime_get_ns() - *tsp) / 1000;
+	hist_add(&runq, pid, delta_us);
+
+	bpf_map_delete_elem(&runnable, &pid);
+}
+
+SEC("tp_btf/sched_wakeup")
+int mole_sched_wakeup(u64 *ctx)
+{
+	/* TP_PROTO(struct task_struct *p) */
+	struct task_struct *p = (struct task_struct *)ctx[0];
+
+	if (is_target(p))
+		trace_runnable(p->pid);
+
+	return 0;
+}
+
+SEC("tp_btf/sched_wakeup_new")
+int mole_sched_wakeup_new(u64 *ctx)
+{
+	/* TP_PROTO(struct task_struct *p) */
+	struct task_struct *p = (struct task_struct *)ctx[0];
+
+	if (is_target(p))
+		trace_runnable(p->pid);
+
+	return 0;
+}
+
+SEC("tp_btf/sched_migrate_task")
+int mole_sched_migrate_task(u64 *ctx)
+{
+	/* TP_PROTO(struct task_struct *p, int dest_cpu) */
+	struct task_struct *p = (struct task_struct *)ctx[0];
+	u32 dest_cpu = ctx[1];
+	u32 from;
+
+	if (!want_placement || !is_target(p))
+		return 0;
+
+	/*
+	 * The cpu it's on now rather than the one it last ran on, it can be
+	 * moved several times before it runs again.
+	 */
+	from = task_cpu(p);
+	if (from != dest_cpu)
+		count_cpu_move(&migrations, p->pid, from, dest_cpu);
+
+	return 0;
+}
+
+SEC("tp_btf/sched_process_fork")
+int mole_sched_process_fork(u64 *ctx)
+{
+	/* TP_PROTO(struct task_struct *parent, struct task_struct *child) */
+	struct task_struct *parent = (struct task_struct *)ctx[0];
+	struct task_struct *child = (struct task_struct *)ctx[1];
+	struct thread_life life = {};
+	struct life_key key = {};
+
+	if (!want_lifecycle || !is_target(child))
+		return 0;
+
+	key.pid = child->pid;
+	key.start = child->start_time;
+	life.born = bpf_ktime_get_ns();
+	life.tgid = child->tgid;
+	life.parent = parent->pid;
+	bpf_probe_read_kernel_str(life.comm, sizeof(life.comm), child->comm);
+
+	bpf_map_update_elem(&lifecycle, &key, &life, BPF_NOEXIST);
+
+	return 0;
+}
+
+SEC("tp_btf/sched_process_exit")
+int mole_sched_process_exit(u64 *ctx)
+{
+	/* TP_PROTO(struct task_struct *p) */
+	struct task_struct *p = (struct task_struct *)ctx[0];
+	struct thread_life *life, init = {};
+	struct thread_exit exit = {};
+	struct life_key key = {};
+	u32 pid;
+
+	if (!is_target(p))
+		return 0;
+
+	/* start is cleaned up by the last switch, which is still to come */
+	pid = p->pid;
+	bpf_map_delete_elem(&runnable, &pid);
+	bpf_map_delete_elem(&switched_out, &pid);
+
+	/* procfs entries of threads are gone right after they exit */
+	exit.utime = p->utime;
+	exit.stime = p->stime;
+	exit.nvcsw = p->nvcsw;
+	exit.nivcsw = p->nivcsw;
+	exit.on_cpu = p->se.sum_exec_runtime;
+	exit.run_delay = p->sched_info.run_delay;
+	exit.pcount = p->sched_info.pcount;
+	exit.tgid = p->tgid;
+	bpf_probe_read_kernel_str(exit.comm, sizeof(exit.comm), p->comm);
+	bpf_map_update_elem(&exits, &pid, &exit, 0);
+
+	if (!want_lifecycle)
+		return 0;
+
+	key.pid = pid;
+	key.start = p->start_time;
+	life = bpf_map_lookup_elem(&lifecycle, &key);
+	if (!life) {
+		/* created before tracing started */
+		init.tgid = p->tgid;
+		bpf_map_update_elem(&lifecycle, &key, &init, BPF_NOEXIST);
+		life = bpf_map_lookup_elem(&lifecycle, &key);
+		if (!life)
+			return 0;
+	}
+
+	/* the name could have been changed since the fork */
+	bpf_probe_read_kernel_str(life->comm, sizeof(life->comm), p->comm);
+	life->on_cpu = p->se.sum_exec_runtime;
+	life->exited = bpf_ktime_get_ns();
+
+	return 0;
+}
+
 static inline long get_task_state(struct task_struct *t)
 {
 	if (bpf_core_field_exists(t->__state))
@@ -73,6 +725,165 @@ static inline long get_task_state(struct task_struct *t)
 	return ((struct task_struct___pre_5_14*)t)->state;
 }
 
+static __always_inline u32 offcpu_state(long state)
+{
+	if (state == TASK_RUNNING)
+		return OFFCPU_PREEMPTED;
+	if (state & TASK_INTERRUPTIBLE)
+		return OFFCPU_SLEEP;
+	if (state & TASK_UNINTERRUPTIBLE)
+		return OFFCPU_DSLEEP;
+
+	return OFFCPU_OTHER;
+}
+
+/* Called in the context of the task which is switching out */
+static __always_inline void trace_switch_out(void *ctx, u32 pid, long state)
+{
+	struct switch_out so = {
+		.ts = bpf_ktime_get_ns(),
+		.state = offcpu_state(state),
+		.kern_stack = -1,
+		.user_stack = -1,
+	};
+
+	if (want_offcpu_stacks && so.state != OFFCPU_PREEMPTED) {
+		so.kern_stack = get_stackid(ctx, 0);
+		if (want_user_stacks)
+			so.user_stack = get_stackid(ctx, BPF_F_USER_STACK);
+	}
+
+	bpf_map_update_elem(&switched_out, &pid, &so, 0);
+}
+
+static __always_inline void account_offcpu_stack(u32 pid,
+						 struct switch_out *so,
+						 u64 delta_us)
+{
+	struct stack_key key = {
+		.pid = pid,
+		.kern_stack = so->kern_stack,
+		.user_stack = so->user_stack,
+	};
+	u64 *total;
+
+	if (key.kern_stack < 0 && key.user_stack < 0)
+		return;
+
+	total = bpf_map_lookup_elem(&offcpu_stacks, &key);
+	if (total) {
+		__sync_fetch_and_add(total, delta_us);
+		return;
+	}
+
+	bpf_map_update_elem(&offcpu_stacks, &key, &delta_us, BPF_NOEXIST);
+}
+
+static __always_inline void account_cpu_time(u32 pid, u64 delta_us)
+{
+	struct cpu_key key = {
+		.pid = pid,
+		.cpu = bpf_get_smp_processor_id(),
+	};
+	u64 *total;
+
+	total = bpf_map_lookup_elem(&cpu_time, &key);
+	if (total) {
+		__sync_fetch_and_add(total, delta_us);
+		return;
+	}
+
+	bpf_map_update_elem(&cpu_time, &key, &delta_us, BPF_NOEXIST);
+}
+
+static __always_inline void trace_switch_in(u32 pid)
+{
+	struct switch_out *so;
+	struct offcpu *oc;
+	u64 delta_us;
+	u32 state;
+
+	so = bpf_map_lookup_elem(&switched_out, &pid);
+	if (!so)
+		return;
+
+	delta_us = (bpf_ktime_get_ns() - so->ts) / 1000;
+	state = so->state;
+	if (want_offcpu_stacks)
+		account_offcpu_stack(pid, so, delta_us);
+	bpf_map_delete_elem(&switched_out, &pid);
+
+	oc = bpf_map_lookup_elem(&offcpu, &pid);
+	if (!oc) {
+		bpf_map_update_elem(&offcpu, &pid, &zero_offcpu, BPF_NOEXIST);
+		oc = bpf_map_lookup_elem(&offcpu, &pid);
+		if (!oc)
+			return;
+	}
+
+	if (state >= NR_OFFCPU)
+		return;
+
+	__sync_fetch_and_add(&oc->count[state], 1);
+	__sync_fetch_and_add(&oc->time[state], delta_us);
+}
+
+static __always_inline void add_u64(void *map, void *key, u64 value)
+{
+	u64 *total;
+
+	total = bpf_map_lookup_elem(map, key);
+	if (total) {
+		__sync_fetch_and_add(total, value);
+		return;
+	}
+
+	bpf_map_update_elem(map, key, &value, BPF_NOEXIST);
+}
+
+/* The time prev spent on this cpu goes to others unless it's ours or idle */
+static __always_inline void account_cpu_share(struct task_struct *prev)
+{
+	u32 cpu = bpf_get_smp_processor_id();
+	u64 now = bpf_ktime_get_ns();
+	u64 *ts, delta_us;
+
+	ts = bpf_map_lookup_elem(&cpu_switch_ts, &cpu);
+	if (!ts)
+		return;
+
+	if (*ts && prev->pid && !is_target(prev)) {
+		delta_us = (now - *ts) / 1000;
+		add_u64(&cpu_other, &cpu, delta_us);
+	}
+
+	*ts = now;
+}
+
+/* The name and policy are taken when the preemptor is first seen */
+static __always_inline void count_preemption(struct task_struct *next)
+{
+	/* zeroed as a whole, the key has trailing padding */
+	struct preempt_key key = {};
+	struct preemptor *p, init = {
+		.policy = next->policy,
+	};
+
+	key.tgidpid = tgidpid(next->tgid, next->pid);
+	key.cpu = bpf_get_smp_processor_id();
+
+	p = bpf_map_lookup_elem(&preemptions, &key);
+	if (!p) {
+		bpf_probe_read_kernel_str(init.comm, sizeof(init.comm), next->comm);
+		bpf_map_update_elem(&preemptions, &key, &init, BPF_NOEXIST);
+		p = bpf_map_lookup_elem(&preemptions, &key);
+		if (!p)
+			return;
+	}
+
+	__sync_fetch_and_add(&p->count, 1);
+}
+
 SEC("tp_btf/sched_switch")
 int mole_sched_switch(u64 *ctx)
 {
@@ -81,29 +892,51 @@ int mole_sched_switch(u64 *ctx)
 	 */
 	struct task_struct *prev = (struct task_struct *)ctx[1];
 	struct task_struct *next = (struct task_struct *)ctx[2];
-	struct event event = {};
 	u64 *tsp, delta_us;
 	long state = get_task_state(prev);
 	u32 pid;
 
-	if (next->tgid == tgid)
+	if (want_cpu_share)
+		account_cpu_share(prev);
+
+	/* blocked, what it wakes up later on doesn't continue its chain */
+	if (want_chains && state != TASK_RUNNING) {
+		pid = prev->pid;
+		bpf_map_delete_elem(&chains, &pid);
+	}
+
+	if (is_target(next)) {
 		trace_enqueue(next->pid);
+		trace_run(next->pid);
+		trace_switch_in(next->pid);
+		if (want_chains)
+			finish_chain(next->pid);
+		if (want_placement)
+			trace_placement(next->pid);
+	}
 
-	if (prev->tgid == tgid) {
+	if (is_target(prev)) {
 		pid = prev->pid;
 
+		/* preempted, still waiting for the cpu */
+		if (state == TASK_RUNNING) {
+			trace_runnable(pid);
+			if ((want_cpu_share || want_preemptors) &&
+			    next->pid && !is_target(next))
+				count_preemption(next);
+		}
+
+		/* the last switch of an exiting thread, it won't be back */
+		if (!prev->exit_state)
+			trace_switch_out(ctx, pid, state);
+
 		tsp = bpf_map_lookup_elem(&start, &pid);
 		if (!tsp)
 			return 0;
 
 		delta_us = (bpf_ktime_get_ns() - *tsp) / 1000;
-
-		event.kind = 1;
-		event.src_tgidpid = pid;
-		event.tgt_tgidpid = delta_us;
-
-		bpf_perf_event_output(ctx, &events, BPF_F_CURRENT_CPU,
-				      &event, sizeof(event));
+		hist_add(&slices, pid, delta_us);
+		account_cpu_time(pid, delta_us);
 
 		bpf_map_delete_elem(&start, &pid);
 	}
@@ -1,10 +1,136 @@
 #ifndef __MOLE_H
 #define __MOLE_H
 
-struct event {
-	unsigned long kind;
+#define HIST_SLOTS 128
+#define MAX_TARGETS 1024
+#define MAX_CPUS 1024
+#define COMM_LEN 16
+
+/* Indexes in the dropped map */
+#define DROPPED_WAKEUPS 0
+#define DROPPED_STACKS 1
+#define NR_DROPPED 2
+
+struct wakeup_key {
 	unsigned long src_tgidpid;
 	unsigned long tgt_tgidpid;
 };
 
+/* Task state at switch-out, indexes in struct offcpu */
+#define OFFCPU_PREEMPTED 0
+#define OFFCPU_SLEEP 1
+#define OFFCPU_DSLEEP 2
+#define OFFCPU_OTHER 3
+#define NR_OFFCPU 4
+
+struct offcpu {
+	unsigned long count[NR_OFFCPU];
+	unsigned long time[NR_OFFCPU]; /* us */
+};
+
+/* On-cpu time is accounted per thread and cpu */
+struct cpu_key {
+	unsigned int pid;
+	unsigned int cpu;
+};
+
+/* Migrations and wakeups are counted per thread and pair of cpus */
+struct cpu_move_key {
+	unsigned int pid;
+	unsigned int from; /* last cpu the thread ran on, or the waker's */
+	unsigned int to;
+};
+
+/* Threads preempting targets, counted per cpu */
+struct preempt_key {
+	unsigned long tgidpid;
+	unsigned int cpu;
+};
+
+struct preemptor {
+	unsigned long count;
+	unsigned int policy; /* SCHED_* */
+	char comm[COMM_LEN];
+};
+
+/* Creation and exit of a thread, timestamps are ns since boot */
+/* Pids get reused, a thread is told apart by its start time too */
+struct life_key {
+	unsigned int pid;
+	unsigned long start; /* ns since boot */
+};
+
+struct thread_life {
+	unsigned long born; /* 0 if created before it was traced */
+	unsigned long exited;
+	unsigned long on_cpu; /* ns, at exit */
+	unsigned int tgid;
+	unsigned int parent;
+	char comm[COMM_LEN];
+};
+
+/* Counters of a thread at exit, the same as procfs would show */
+struct thread_exit {
+	unsigned long utime; /* ns */
+	unsigned long stime; /* ns */
+	unsigned long nvcsw;
+	unsigned long nivcsw;
+	unsigned long on_cpu; /* ns */
+	unsigned long run_delay; /* ns */
+	unsigned long pcount;
+	unsigned int tgid;
+	char comm[COMM_LEN];
+};
+
+#define MAX_STACK_DEPTH 127
+
+/* Off-cpu time is accounted per thread and stack */
+struct stack_key {
+	unsigned int pid;
+	int kern_stack; /* stack ids, negative if not captured */
+	int user_stack;
+};
+
+/* Wakeups are counted per waker stack when stacks are enabled */
+struct wakeup_stack_key {
+	unsigned long src_tgidpid;
+	unsigned long tgt_tgidpid;
+	int kern_stack;
+	int user_stack;
+};
+
+/* Threads on a wakeup chain: A wakes B, B wakes C... */
+#define MAX_CHAIN 6
+
+struct chain {
+	unsigned int len;
+	unsigned int ran; /* the last thread got on a cpu */
+	unsigned int pids[MAX_CHAIN];
+	unsigned long ts[MAX_CHAIN]; /* when pids[i] was woken up, ns */
+};
+
+struct chain_key {
+	unsigned int len;
+	unsigned int pids[MAX_CHAIN];
+};
+
+/*
+ * Hop i is the time from pids[i + 1] being woken up to it waking up the
+ * next thread, or getting on a cpu for the last thread.
+ */
+struct chain_stats {
+	unsigned long count;
+	unsigned long hop_ns[MAX_CHAIN - 1];
+};
+
+/*
+ * Log-linear histogram: values below 4 get a slot each, then every power
+ * of two is split into 4 equal slots.
+ */
+struct hist {
+	unsigned long slots[HIST_SLOTS];
+	unsigned long sum;
+	unsigned long max; /* exact */
+};
+
 #endif /* __MOLE_H */
@@ -0,0 +1,102 @@
+use crate::{
+    bpf, output, table, tgidpid_pid, tgidpid_tgid, thread_comm, ProcessDataSnapshot, TOP_EVENTS,
+};
+use std::collections::HashMap;
+
+//
+// How every cpu was shared between the profiled threads, other processes
+// and interrupts, and how often ours were preempted there. The time of
+// interrupts is also in the time of the tasks they interrupted, so it
+// overlaps with the other columns instead of adding up with them.
+//
+pub fn cpus_table(data: &bpf::Interval) -> output::Table {
+    let mut table = table![
+        ("cpu", 6),
+        ("target_us", 10),
+        ("other_us", 10),
+        ("irq_us", 10),
+        ("softirq_us", 10),
+        ("idle_us", 10),
+        ("target%", 7),
+        ("other%", 7),
+        ("preempted", 10)
+    ];
+    table.sort_by = None; // in the order of cpus
+
+    let mut target: HashMap<u32, u64> = HashMap::new();
+    for ((_, cpu), us) in &data.cpus {
+        *target.entry(*cpu).or_insert(0) += us;
+    }
+
+    let mut preempted: HashMap<u32, u64> = HashMap::new();
+    for ((_, cpu), count) in &data.preemptions {
+        *preempted.entry(*cpu).or_insert(0) += count;
+    }
+
+    let mut cpus: Vec<_> = data.cpu_shares.keys().cloned().collect();
+    cpus.sort_unstable();
+
+    for cpu in cpus {
+        let share = &data.cpu_shares[&cpu];
+        let target = target.get(&cpu).cloned().unwrap_or(0);
+        let total = target + share.other + share.idle;
+        let pct = |us: u64| us as f64 / total.max(1) as f64 * 100.0;
+
+        table.add_row(vec![
+            output::Data::UInt(cpu as u64),
+            output::Data::UInt(target),
+            output::Data::UInt(share.other),
+            output::Data::UInt(share.irq),
+            output::Data::UInt(share.softirq),
+            output::Data::UInt(share.idle),
+            output::Data::Float(pct(target)),
+            output::Data::Float(pct(share.other)),
+            output::Data::UInt(preempted.get(&cpu).cloned().unwrap_or(0)),
+        ]);
+    }
+
+    table
+}
+
+//
+// Threads of other processes which took a cpu from ours while they were still
+// runnable, as they were named when it happened. Cpus are listed from the one
+// with the most preemptions.
+//
+pub fn preemptors_table(data: &bpf::Interval, curr: &ProcessDataSnapshot) -> output::Table {
+    let mut table = table![
+        ("pid", 8),
+        ("tgid", 8),
+        ("comm", 16),
+        ("policy", 8),
+        ("preemptions", 11),
+        ("cpus", 24)
+    ];
+    table.sort_by = Some(4); // sort by preemptions
+    table.top = Some(TOP_EVENTS);
+
+    let mut preemptors: HashMap<u64, Vec<(u32, u64)>> = HashMap::new();
+    for ((tgidpid, cpu), count) in &data.preemptions {
+        preemptors.entry(*tgidpid).or_default().push((*cpu, *count));
+    }
+
+    for (tgidpid, mut cpus) in preemptors {
+        cpus.sort_by_key(|(cpu, count)| (std::cmp::Reverse(*count), *cpu));
+        let list: Vec<_> = cpus.iter().map(|(cpu, _)| cpu.to_string()).collect();
+        let (comm, policy) = match data.preemptors.get(&tgidpid) {
+            Some(task) => (task.comm.clone(), bpf::policy_name(task.policy)),
+            None => (thread_comm(curr, tgidpid), String::new()),
+        };
+
+        table.add_row(vec![
+            output::Data::Int(tgidpid_pid(tgidpid) as i64),
+            output::Data::Int(tgidpid_tgid(tgidpid) as i64),
+            output::Data::Text(comm),
+            output::Data::Text(policy),
+            output::Data::UInt(cpus.iter().map(|(_, count)| count).sum()),
+            output::Data::Text(list.join(" ")),
+        ]);
+    }
+
+    table
+}
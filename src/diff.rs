@@ -0,0 +1,325 @@
+use crate::hist::Histogram;
+use crate::{output, table, tgidpid_pid, ProcessDataSnapshot, Record, ThreadDataSnapshot};
+use chrono::{DateTime, FixedOffset};
+use std::collections::HashMap;
+
+#[derive(Default)]
+struct Totals {
+    name: String, // as last seen
+    utime: u64,
+    stime: u64,
+    on_cpu: u64,
+    wait: u64,
+    slices: u64,
+    vctxsw: u64,
+    ivctxsw: u64,
+}
+
+//
+// Recorded or live intervals summed up per thread, or per thread name with
+// by_comm, so that runs of different length or different builds can be
+// lined up against each other.
+//
+pub struct Profile {
+    by_comm: bool,
+    start: DateTime<FixedOffset>,
+    end: DateTime<FixedOffset>,
+    load: u64,
+    threads: HashMap<String, Totals>,
+    slices: HashMap<String, Histogram>,
+    edges: HashMap<(String, String), u64>,
+}
+
+fn parse_time(time: &str) -> DateTime<FixedOffset> {
+    DateTime::parse_from_rfc3339(time).expect("Invalid time in the recording")
+}
+
+impl Profile {
+    // Starts at the time of the record holding the initial snapshot
+    pub fn new(by_comm: bool, first: &Record) -> Profile {
+        Profile {
+            by_comm,
+            start: parse_time(&first.time),
+            end: parse_time(&first.time),
+            load: 0,
+            threads: HashMap::new(),
+            slices: HashMap::new(),
+            edges: HashMap::new(),
+        }
+    }
+
+    fn key(&self, pid: i32, comm: &str) -> String {
+        if self.by_comm {
+            comm.to_string()
+        } else {
+            pid.to_string()
+        }
+    }
+
+    fn name(&self, pid: i32, comm: &str) -> String {
+        if self.by_comm {
+            comm.to_string()
+        } else {
+            format!("{} ({})", pid, comm)
+        }
+    }
+
+    pub fn add(&mut self, prev: &ProcessDataSnapshot, rec: &Record) {
+        let curr = &rec.snapshot;
+        let zero = ThreadDataSnapshot::default();
+
+        self.end = parse_time(&rec.time);
+        self.load += rec.load;
+
+        // threads which exited are accounted up to their exit
+        for (pid, c) in curr.threads.iter().chain(&curr.exited) {
+            let p = prev.threads.get(pid).unwrap_or(&zero);
+            let name = self.name(*pid, &c.comm);
+            let t = self.threads.entry(self.key(*pid, &c.comm)).or_default();
+
+            t.name = name;
+            t.utime += c.utime.saturating_sub(p.utime);
+            t.stime += c.stime.saturating_sub(p.stime);
+            t.on_cpu += c.on_cpu.saturating_sub(p.on_cpu);
+            t.wait += c.waiting_for_cpu.saturating_sub(p.waiting_for_cpu);
+            t.slices += c.slices.saturating_sub(p.slices);
+            t.vctxsw += c.vctxsw.saturating_sub(p.vctxsw);
+            t.ivctxsw += c.ivctxsw.saturating_sub(p.ivctxsw);
+        }
+
+        for (pid, hist) in &rec.data.slices {
+            let comm = curr.thread(*pid).map_or("unknown", |t| &t.comm);
+            self.slices
+                .entry(self.key(*pid, comm))
+                .or_insert_with(Histogram::new)
+                .merge(hist);
+        }
+
+        let comm = |pid: i32| -> &str {
+            match curr.thread(pid) {
+                Some(t) => &t.comm,
+                None => rec.comms.get(&pid).map_or("unknown", |c| c),
+            }
+        };
+
+        for ((src, tgt), count) in &rec.data.wakeups {
+            let (src, tgt) = (tgidpid_pid(*src), tgidpid_pid(*tgt));
+            let edge = (self.name(src, comm(src)), self.name(tgt, comm(tgt)));
+
+            *self.edges.entry(edge).or_insert(0) += count;
+        }
+    }
+
+    // All records of a recording, the first one holds the initial snapshot
+    pub fn load(mut records: impl Iterator<Item = Record>, by_comm: bool) -> Profile {
+        let first = records.next().expect("Empty recording");
+        let mut profile = Profile::new(by_comm, &first);
+        let mut prev = first.snapshot;
+
+        for rec in records {
+            profile.add(&prev, &rec);
+            prev = rec.snapshot;
+        }
+
+        profile
+    }
+
+    fn seconds(&self) -> f64 {
+        ((self.end - self.start).num_milliseconds() as f64 / 1000.0).max(0.001)
+    }
+}
+
+fn change(base: f64, new: f64) -> f64 {
+    if base == 0.0 {
+        if new == 0.0 {
+            0.0
+        } else {
+            f64::INFINITY
+        }
+    } else {
+        (new - base) / base * 100.0
+    }
+}
+
+pub struct Options {
+    pub sort_by: Option<String>,
+    pub filter_by: Option<String>,
+    pub top: Option<usize>,
+    pub format: output::Format, // text or json
+}
+
+//
+// Per-second rates of the new profile minus the base one and the changes
+// in %, thread usr%/sys% are compared in percentage points.
+//
+fn threads_table(base: &Profile, new: &Profile, opts: &Options) -> output::Table {
+    let mut table = table![
+        ("thread", 24),
+        ("usr%", 6),
+        ("sys%", 6),
+        ("on_cpu", 10),
+        ("on_cpu%", 8),
+        ("wait", 10),
+        ("wait%", 8),
+        ("slices", 10),
+        ("slices%", 8),
+        ("avg_slice", 10),
+        ("avg_slice%", 10),
+        ("vctxsw", 10),
+        ("vctxsw%", 8),
+        ("ivctxsw", 10),
+        ("ivctxsw%", 8)
+    ];
+
+    table.sort_by = Some(3); // sort by on_cpu
+    table.top = opts.top;
+    if let Some(sort_by) = &opts.sort_by {
+        table.sort_by = Some(
+            table
+                .column_index_by_desc(sort_by)
+                .expect("Invalid column specified"),
+        );
+    }
+    if let Some(filter_by) = &opts.filter_by {
+        table.filter_by = Some(
+            table
+                .column_index_by_desc(filter_by)
+                .expect("Invalid column specified"),
+        );
+    }
+
+    let zero = Totals::default();
+    let mut keys: Vec<_> = base.threads.keys().chain(new.threads.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    for key in keys {
+        let b = base.threads.get(key).unwrap_or(&zero);
+        let n = new.threads.get(key).unwrap_or(&zero);
+        let name = if n.name.is_empty() { &b.name } else { &n.name };
+
+        let share = |v: u64, p: &Profile| v as f64 / p.load.max(1) as f64 * 100.0;
+        let rate = |v: u64, p: &Profile| v as f64 / p.seconds();
+        let avg = |t: &Totals| t.on_cpu as f64 / t.slices.max(1) as f64;
+
+        let mut row = vec![
+            output::Data::Text(name.clone()),
+            output::Data::Float(share(n.utime, new) - share(b.utime, base)),
+            output::Data::Float(share(n.stime, new) - share(b.stime, base)),
+        ];
+
+        for (b, n) in [
+            (rate(b.on_cpu, base), rate(n.on_cpu, new)),
+            (rate(b.wait, base), rate(n.wait, new)),
+            (rate(b.slices, base), rate(n.slices, new)),
+            (avg(b), avg(n)),
+            (rate(b.vctxsw, base), rate(n.vctxsw, new)),
+            (rate(b.ivctxsw, base), rate(n.ivctxsw, new)),
+        ] {
+            row.push(output::Data::Float(n - b));
+            row.push(output::Data::Float(change(b, n)));
+        }
+
+        table.add_row(row);
+    }
+
+    table
+}
+
+// Slice percentiles of the new profile and their changes against the base
+fn slices_table(base: &Profile, new: &Profile) -> output::Table {
+    let mut table = table![
+        ("thread", 24),
+        ("p50", 8),
+        ("p50_diff", 8),
+        ("p50%", 8),
+        ("p95", 8),
+        ("p95_diff", 8),
+        ("p95%", 8),
+        ("p99", 8),
+        ("p99_diff", 8),
+        ("p99%", 8),
+        ("max", 8),
+        ("max_diff", 8),
+        ("max%", 8)
+    ];
+
+    table.sort_by = Some(8); // sort by p99_diff
+
+    let empty = Histogram::new();
+    let mut keys: Vec<_> = base.slices.keys().chain(new.slices.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    for key in keys {
+        let b = base.slices.get(key).unwrap_or(&empty);
+        let n = new.slices.get(key).unwrap_or(&empty);
+        let name = match (new.threads.get(key), base.threads.get(key)) {
+            (Some(t), _) | (None, Some(t)) => &t.name,
+            (None, None) => key,
+        };
+
+        let mut row = vec![output::Data::Text(name.clone())];
+        for (bv, nv) in [
+            (b.percentile(50), n.percentile(50)),
+            (b.percentile(95), n.percentile(95)),
+            (b.percentile(99), n.percentile(99)),
+            (b.max(), n.max()),
+        ] {
+            row.push(output::Data::UInt(nv));
+            row.push(output::Data::Int(nv as i64 - bv as i64));
+            row.push(output::Data::Float(change(bv as f64, nv as f64)));
+        }
+
+        table.add_row(row);
+    }
+
+    table
+}
+
+// Wakeup edges seen in only one of the profiles
+fn edges_table(base: &Profile, new: &Profile) -> output::Table {
+    let mut table = table![("edge", 8), ("waker", 24), ("wakee", 24), ("wakeups/s", 10)];
+
+    table.sort_by = Some(3); // sort by wakeups/s
+
+    for (change, from, to) in [("appeared", new, base), ("gone", base, new)] {
+        for ((waker, wakee), count) in &from.edges {
+            if to.edges.contains_key(&(waker.clone(), wakee.clone())) {
+                continue;
+            }
+
+            table.add_row(vec![
+                output::Data::Text(change.to_string()),
+                output::Data::Text(waker.clone()),
+                output::Data::Text(wakee.clone()),
+                output::Data::Float(*count as f64 / from.seconds()),
+            ]);
+        }
+    }
+
+    table
+}
+
+pub fn print_diff(base: &Profile, new: &Profile, opts: &Options) {
+    if opts.format == output::Format::Json {
+        let doc = serde_json::json!({
+            "seconds": new.seconds(),
+            "base_seconds": base.seconds(),
+            "threads": threads_table(base, new, opts).json_rows(),
+            "slices": slices_table(base, new).json_rows(),
+            "edges": edges_table(base, new).json_rows(),
+        });
+        println!("{}", doc);
+        return;
+    }
+
+    println!(
+        "{:.1}s against {:.1}s of the base, per-second differences",
+        new.seconds(),
+        base.seconds()
+    );
+    println!("{}", threads_table(base, new, opts).display_table());
+    println!("{}", slices_table(base, new).display_table());
+    println!("{}", edges_table(base, new).display_table());
+}
@@ -0,0 +1,197 @@
+use crate::hist::{self, Histogram};
+use crate::{
+    bpf, output, table, tgidpid_pid, top_events_table, ProcessDataSnapshot, Record,
+    ThreadDataSnapshot,
+};
+use std::collections::HashMap;
+
+// Deltas of the thread counters over the interval
+fn counters_table(
+    tid: i32,
+    prev: &ProcessDataSnapshot,
+    curr: &ProcessDataSnapshot,
+) -> output::Table {
+    let mut table = table![
+        ("on_cpu", 10),
+        ("wait", 10),
+        ("slices", 10),
+        ("avg_slice", 10),
+        ("vctxsw", 10),
+        ("ivctxsw", 10)
+    ];
+
+    // counters at exit may be a bit off from procfs, hence saturating
+    if let Some(c) = curr.thread(tid) {
+        let zero = ThreadDataSnapshot::default();
+        let p = prev.threads.get(&tid).unwrap_or(&zero);
+        let slices = c.slices.saturating_sub(p.slices);
+        let on_cpu = c.on_cpu.saturating_sub(p.on_cpu);
+
+        table.add_row(vec![
+            output::Data::UInt(on_cpu),
+            output::Data::UInt(c.waiting_for_cpu.saturating_sub(p.waiting_for_cpu)),
+            output::Data::UInt(slices),
+            output::Data::UInt(on_cpu.checked_div(slices).unwrap_or(0)),
+            output::Data::UInt(c.vctxsw.saturating_sub(p.vctxsw)),
+            output::Data::UInt(c.ivctxsw.saturating_sub(p.ivctxsw)),
+        ]);
+    }
+
+    table
+}
+
+// Every non-empty slot of a histogram with a bar scaled to the largest one
+fn hist_table(h: Option<&Histogram>) -> output::Table {
+    let mut table = table![
+        ("from_us", 10),
+        ("to_us", 10),
+        ("count", 10),
+        ("%", 6),
+        ("distribution", 40)
+    ];
+
+    let h = match h {
+        Some(h) => h,
+        None => return table,
+    };
+    let total = h.count().max(1);
+    let peak = h.slots.iter().max().cloned().unwrap_or(0).max(1);
+
+    for (slot, n) in h.slots.iter().enumerate().filter(|(_, n)| **n > 0) {
+        // the last slot is open-ended
+        let to = if slot + 1 < hist::SLOTS {
+            output::Data::UInt(hist::slot_value(slot + 1) - 1)
+        } else {
+            output::Data::Text("inf".to_string())
+        };
+
+        table.add_row(vec![
+            output::Data::UInt(hist::slot_value(slot)),
+            to,
+            output::Data::UInt(*n),
+            output::Data::Float(*n as f64 / total as f64 * 100.0),
+            output::Data::Text("#".repeat((n * 40 / peak) as usize)),
+        ]);
+    }
+
+    table
+}
+
+fn cpus_table(tid: i32, cpus: &bpf::CpuTime) -> output::Table {
+    let mut table = table![("cpu", 6), ("on_cpu", 10), ("%", 6)];
+    table.sort_by = Some(0); // sort by cpu
+
+    let total: u64 = cpus
+        .iter()
+        .filter(|((pid, _), _)| *pid == tid)
+        .map(|(_, us)| us)
+        .sum();
+
+    for ((pid, cpu), us) in cpus {
+        if *pid == tid {
+            table.add_row(vec![
+                output::Data::UInt(*cpu as u64),
+                output::Data::UInt(*us),
+                output::Data::Float(*us as f64 / total.max(1) as f64 * 100.0),
+            ]);
+        }
+    }
+
+    table
+}
+
+fn offcpu_table(offcpu: Option<&bpf::OffCpu>) -> output::Table {
+    let mut table = table![
+        ("state", 10),
+        ("count", 10),
+        ("time_us", 10),
+        ("avg_us", 10),
+        ("%", 6)
+    ];
+    table.sort_by = None; // in the order of OFFCPU_STATES
+
+    let oc = match offcpu {
+        Some(oc) => oc,
+        None => return table,
+    };
+    let total: u64 = oc.time.iter().sum();
+
+    for (i, state) in bpf::OFFCPU_STATES.iter().enumerate() {
+        table.add_row(vec![
+            output::Data::Text(state.to_string()),
+            output::Data::UInt(oc.count[i]),
+            output::Data::UInt(oc.time[i]),
+            output::Data::UInt(oc.time[i].checked_div(oc.count[i]).unwrap_or(0)),
+            output::Data::Float(oc.time[i] as f64 / total.max(1) as f64 * 100.0),
+        ]);
+    }
+
+    table
+}
+
+//
+// Everything known about one thread over the interval: counters, full
+// slice and run queue latency histograms, all wakers and wakees, CPUs it
+// ran on and where its off-cpu time went.
+//
+pub fn sections(
+    tid: i32,
+    prev: &ProcessDataSnapshot,
+    rec: &Record,
+) -> Vec<(&'static str, output::Table)> {
+    let curr = &rec.snapshot;
+    let data = &rec.data;
+    let mut wakers: HashMap<u64, u64> = HashMap::new();
+    let mut wakees: HashMap<u64, u64> = HashMap::new();
+
+    for ((src, tgt), count) in &data.wakeups {
+        if tgidpid_pid(*tgt) == tid {
+            *wakers.entry(*src).or_insert(0) += count;
+        }
+        if tgidpid_pid(*src) == tid {
+            *wakees.entry(*tgt).or_insert(0) += count;
+        }
+    }
+
+    vec![
+        ("counters", counters_table(tid, prev, curr)),
+        ("slices", hist_table(data.slices.get(&tid))),
+        ("runq", hist_table(data.runq.get(&tid))),
+        ("wakers", top_events_table(&wakers, curr, None)),
+        ("wakees", top_events_table(&wakees, curr, None)),
+        ("cpus", cpus_table(tid, &data.cpus)),
+        ("offcpu", offcpu_table(data.offcpu.get(&tid))),
+    ]
+}
+
+// Thread name and process, e.g. "1234 (worker) of 1200"
+pub fn title(tid: i32, curr: &ProcessDataSnapshot) -> String {
+    match (curr.threads.get(&tid), curr.exited.get(&tid)) {
+        (Some(t), _) => format!("{} ({}) of {}", tid, t.comm, t.tgid),
+        (None, Some(t)) => format!("{} ({}) of {}, exited", tid, t.comm, t.tgid),
+        (None, None) => format!("{} (gone)", tid),
+    }
+}
+
+pub fn show(format: output::Format, tid: i32, prev: &ProcessDataSnapshot, rec: &Record) {
+    match format {
+        output::Format::Json => {
+            let mut doc = serde_json::json!({
+                "time": rec.time,
+                "tid": tid,
+                "comm": rec.snapshot.thread(tid).map(|t| &t.comm),
+            });
+            for (name, mut table) in sections(tid, prev, rec) {
+                doc[name] = table.json_rows();
+            }
+            println!("{}", doc);
+        }
+        _ => {
+            println!("thread {}", title(tid, &rec.snapshot));
+            for (name, mut table) in sections(tid, prev, rec) {
+                println!("{}", name);
+                println!("{}", table.display_table());
+            }
+        }
+    }
+}
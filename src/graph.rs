@@ -0,0 +1,169 @@
+use crate::{tgidpid_pid, tgidpid_tgid, Record};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+//
+// Wakeups seen since the start as a directed graph between threads, named
+// after the latest record mentioning them.
+//
+#[derive(Default)]
+pub struct Graph {
+    edges: HashMap<(u64, u64), u64>, // (src_tgidpid, tgt_tgidpid) -> wakeups
+    comms: HashMap<i32, String>,
+}
+
+impl Graph {
+    pub fn add(&mut self, rec: &Record) {
+        for ((src, tgt), count) in &rec.data.wakeups {
+            *self.edges.entry((*src, *tgt)).or_insert(0) += count;
+
+            for pid in [tgidpid_pid(*src), tgidpid_pid(*tgt)] {
+                let comm = match rec.snapshot.thread(pid) {
+                    Some(t) => Some(&t.comm),
+                    None => rec.comms.get(&pid),
+                };
+                if let Some(comm) = comm {
+                    self.comms.insert(pid, comm.clone());
+                }
+            }
+        }
+    }
+
+    fn comm(&self, pid: i32) -> &str {
+        self.comms.get(&pid).map_or("unknown", |c| c)
+    }
+
+    // Edges with at least min wakeups, sorted for a stable output
+    fn edges(&self, min: u64) -> Vec<((u64, u64), u64)> {
+        let mut edges: Vec<_> = self
+            .edges
+            .iter()
+            .filter(|(_, count)| **count >= min)
+            .map(|(edge, count)| (*edge, *count))
+            .collect();
+        edges.sort_unstable();
+
+        edges
+    }
+
+    // Threads are grouped into a cluster per process
+    pub fn write_dot(&self, out: &mut impl Write, min: u64) -> std::io::Result<()> {
+        let edges = self.edges(min);
+        let max = edges.iter().map(|(_, count)| *count).max().unwrap_or(1);
+
+        let mut procs: BTreeMap<i32, Vec<i32>> = BTreeMap::new();
+        for ((src, tgt), _) in &edges {
+            for tgidpid in [*src, *tgt] {
+                procs
+                    .entry(tgidpid_tgid(tgidpid))
+                    .or_default()
+                    .push(tgidpid_pid(tgidpid));
+            }
+        }
+
+        writeln!(out, "digraph wakeups {{")?;
+        writeln!(out, "    node [shape=box];")?;
+
+        for (tgid, mut pids) in procs {
+            pids.sort_unstable();
+            pids.dedup();
+
+            writeln!(out, "    subgraph cluster_{} {{", tgid)?;
+            writeln!(
+                out,
+                "        label=\"{} ({})\";",
+                tgid,
+                dot_escape(self.comm(tgid))
+            )?;
+            for pid in pids {
+                writeln!(
+                    out,
+                    "        t{} [label=\"{}\\n{}\"];",
+                    pid,
+                    pid,
+                    dot_escape(self.comm(pid))
+                )?;
+            }
+            writeln!(out, "    }}")?;
+        }
+
+        // the busiest edges are drawn up to 5 times thicker
+        for ((src, tgt), count) in edges {
+            writeln!(
+                out,
+                "    t{} -> t{} [label=\"{}\", penwidth={:.1}];",
+                tgidpid_pid(src),
+                tgidpid_pid(tgt),
+                count,
+                1.0 + 4.0 * count as f64 / max as f64
+            )?;
+        }
+
+        writeln!(out, "}}")?;
+        out.flush()
+    }
+
+    // Every thread with the threads it woke up
+    pub fn to_json(&self, min: u64) -> serde_json::Value {
+        let mut nodes: BTreeMap<u64, Vec<serde_json::Value>> = BTreeMap::new();
+
+        for ((src, tgt), count) in self.edges(min) {
+            nodes.entry(tgt).or_default();
+            nodes.entry(src).or_default().push(serde_json::json!({
+                "tid": tgidpid_pid(tgt),
+                "wakeups": count,
+            }));
+        }
+
+        nodes
+            .into_iter()
+            .map(|(tgidpid, wakees)| {
+                serde_json::json!({
+                    "tid": tgidpid_pid(tgidpid),
+                    "tgid": tgidpid_tgid(tgidpid),
+                    "comm": self.comm(tgidpid_pid(tgidpid)),
+                    "wakees": wakees,
+                })
+            })
+            .collect()
+    }
+
+    // JSON for *.json files, DOT otherwise
+    pub fn write(&self, path: &Path, min: u64) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+            serde_json::to_writer_pretty(&mut out, &self.to_json(min))?;
+            writeln!(out)?;
+            out.flush()
+        } else {
+            self.write_dot(&mut out, min)
+        }
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[test]
+fn dot_threshold() {
+    let mut g = Graph::default();
+    g.edges.insert((1 << 32 | 1, 1 << 32 | 2), 10);
+    g.edges.insert((1 << 32 | 2, 3 << 32 | 3), 1);
+    g.comms.insert(1, "main".to_string());
+
+    let mut out = vec![];
+    g.write_dot(&mut out, 2).unwrap();
+    let dot = String::from_utf8(out).unwrap();
+
+    assert!(dot.contains("label=\"1 (main)\""));
+    assert!(dot.contains("t1 -> t2 [label=\"10\", penwidth=5.0];"));
+    assert!(!dot.contains("t3"));
+
+    let json = g.to_json(0);
+    assert_eq!(json.as_array().unwrap().len(), 3);
+    assert_eq!(json[0]["wakees"][0]["tid"], 2);
+}
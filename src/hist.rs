@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+
+// Userspace side of `struct hist` from mole.h: values below 4 have a slot
+// each, then every power of two is split into 4 equal slots.
+pub const SLOTS: usize = 128;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Histogram {
+    pub slots: Vec<u64>,
+    pub sum: u64,       // of the exact values
+    pub max_value: u64, // exact
+}
+
+// Smallest value falling into the slot
+pub fn slot_value(slot: usize) -> u64 {
+    if slot < 4 {
+        return slot as u64;
+    }
+
+    let l = slot / 4 + 1;
+    let sub = (slot % 4) as u64;
+
+    (1 << l) + sub * (1 << (l - 2))
+}
+
+#[cfg(test)]
+fn slot_index(v: u64) -> usize {
+    if v < 4 {
+        return v as usize;
+    }
+
+    let l = 63 - v.leading_zeros() as usize;
+    let slot = (l - 1) * 4 + ((v >> (l - 2)) & 3) as usize;
+
+    slot.min(SLOTS - 1)
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            slots: vec![0; SLOTS],
+            sum: 0,
+            max_value: 0,
+        }
+    }
+
+    pub fn from_slots(slots: &[u64], sum: u64, max_value: u64) -> Histogram {
+        Histogram {
+            slots: slots.to_vec(),
+            sum,
+            max_value,
+        }
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (a, b) in self.slots.iter_mut().zip(&other.slots) {
+            *a += b;
+        }
+        self.sum += other.sum;
+        self.max_value = self.max_value.max(other.max_value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.slots.iter().sum()
+    }
+
+    // Lower bound of the slot holding the p-th percentile, 0 <= p <= 100
+    pub fn percentile(&self, p: u64) -> u64 {
+        let count = self.count();
+        if count == 0 {
+            return 0;
+        }
+
+        let rank = (count * p / 100).min(count - 1);
+        let mut seen = 0;
+        for (slot, n) in self.slots.iter().enumerate() {
+            seen += n;
+            if seen > rank {
+                return slot_value(slot);
+            }
+        }
+
+        unreachable!()
+    }
+
+    pub fn min(&self) -> u64 {
+        self.percentile(0)
+    }
+
+    // The exact maximum if known, the upper bound of the last slot otherwise
+    pub fn max(&self) -> u64 {
+        let slot = match self.slots.iter().rposition(|n| *n > 0) {
+            Some(slot) => slot,
+            None => return 0,
+        };
+
+        if self.max_value >= slot_value(slot) {
+            return self.max_value;
+        }
+
+        // the last slot is open-ended
+        if slot + 1 < SLOTS {
+            slot_value(slot + 1) - 1
+        } else {
+            slot_value(slot)
+        }
+    }
+}
+
+#[test]
+fn hist_slots() {
+    for v in (0..100000).chain(vec![1 << 32, u32::MAX as u64]) {
+        let slot = slot_index(v);
+        assert!(slot_value(slot) <= v);
+        assert!(v < slot_value(slot + 1));
+    }
+
+    let mut h = Histogram::new();
+    for v in 1..=100 {
+        h.slots[slot_index(v)] += 1;
+    }
+
+    assert_eq!(h.count(), 100);
+    assert_eq!(h.min(), 1);
+    assert_eq!(h.percentile(50), 48);
+    assert_eq!(h.max(), 111);
+
+    h.max_value = 100;
+    assert_eq!(h.max(), 100);
+}
@@ -0,0 +1,83 @@
+use anyhow::{bail, Result};
+use std::ffi::CString;
+
+pub struct Child {
+    pub pid: i32,
+    go: Option<i32>, // write end of the pipe the child waits on before exec
+}
+
+//
+// Fork and prepare to exec the command. The child is held back until
+// resume() is called, so the BPF programs can be attached before it runs
+// its first instruction.
+//
+pub fn spawn(cmd: &[String]) -> Result<Child> {
+    let args = cmd
+        .iter()
+        .map(|s| CString::new(s.as_str()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let mut argv: Vec<*const libc::c_char> = args.iter().map(|a| a.as_ptr()).collect();
+    argv.push(std::ptr::null());
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        bail!("Failed to create a pipe");
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => bail!("Failed to fork"),
+        0 => unsafe {
+            let mut buf = 0u8;
+
+            libc::close(fds[1]);
+            // EOF means the parent went away without releasing us
+            if libc::read(fds[0], &mut buf as *mut u8 as *mut libc::c_void, 1) == 1 {
+                libc::close(fds[0]);
+                libc::execvp(argv[0], argv.as_ptr());
+                libc::perror(b"mole: exec\0".as_ptr() as *const libc::c_char);
+            }
+            libc::_exit(127);
+        },
+        pid => {
+            unsafe { libc::close(fds[0]) };
+            Ok(Child {
+                pid,
+                go: Some(fds[1]),
+            })
+        }
+    }
+}
+
+impl Child {
+    pub fn resume(&mut self) {
+        if let Some(fd) = self.go.take() {
+            unsafe {
+                libc::write(fd, b"g".as_ptr() as *const libc::c_void, 1);
+                libc::close(fd);
+            }
+        }
+    }
+
+    // Returns the exit code once the child has terminated (and reaps it)
+    pub fn try_wait(&self) -> Option<i32> {
+        let mut status = 0;
+
+        if unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) } != self.pid {
+            return None;
+        }
+
+        if libc::WIFEXITED(status) {
+            Some(libc::WEXITSTATUS(status))
+        } else {
+            Some(128 + libc::WTERMSIG(status))
+        }
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        if let Some(fd) = self.go.take() {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
@@ -1,14 +1,34 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
-use std::time::Duration;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use structopt::StructOpt;
 
 mod bpf;
+mod cpus;
+mod diff;
+mod focus;
+mod graph;
+mod hist;
+mod launch;
+mod metrics;
 mod output;
 mod procfs;
+mod syms;
+mod system;
+mod topology;
+mod tui;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 struct ThreadDataSnapshot {
     pid: i32,
+    tgid: i32,
     comm: String,
     utime: u64,
     stime: u64,
@@ -17,6 +37,20 @@ struct ThreadDataSnapshot {
     on_cpu: u64,
     waiting_for_cpu: u64,
     slices: u64,
+    cpu: u32, // the last one it ran on
+}
+
+impl ThreadDataSnapshot {
+    // Sums up counters of deltas
+    fn merge(&mut self, other: &ThreadDataSnapshot) {
+        self.utime += other.utime;
+        self.stime += other.stime;
+        self.vctxsw += other.vctxsw;
+        self.ivctxsw += other.ivctxsw;
+        self.on_cpu += other.on_cpu;
+        self.waiting_for_cpu += other.waiting_for_cpu;
+        self.slices += other.slices;
+    }
 }
 
 fn inspect_thread(tgid: i32, pid: i32) -> Option<ThreadDataSnapshot> {
@@ -26,6 +60,7 @@ fn inspect_thread(tgid: i32, pid: i32) -> Option<ThreadDataSnapshot> {
 
     let ret = ThreadDataSnapshot {
         pid: pid,
+        tgid,
         comm: status.name,
         utime: stat.utime,
         stime: stat.stime,
@@ -34,26 +69,75 @@ fn inspect_thread(tgid: i32, pid: i32) -> Option<ThreadDataSnapshot> {
         on_cpu: schedstat.on_cpu / 1000, // nanoseconds to microseconds
         waiting_for_cpu: schedstat.waiting_for_cpu / 1000, // nanoseconds to microseconds
         slices: schedstat.slices,
+        cpu: stat.processor,
     };
 
     Some(ret)
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 struct ProcessDataSnapshot {
-    pid: i32,
+    tgids: Vec<i32>,
     threads: HashMap<i32, ThreadDataSnapshot>,
+    exited: HashMap<i32, ThreadDataSnapshot>, // during the interval, counters at exit
+}
+
+impl ProcessDataSnapshot {
+    // Whether the thread group is one of the profiled processes
+    fn has(&self, tgid: i32) -> bool {
+        self.tgids.contains(&tgid)
+    }
+
+    // Live threads first, then the ones which exited during the interval
+    fn thread(&self, pid: i32) -> Option<&ThreadDataSnapshot> {
+        self.threads.get(&pid).or_else(|| self.exited.get(&pid))
+    }
+}
+
+// Processes to profile: the given ones or whatever is in a cgroup
+enum Targets {
+    Pids(Vec<i32>),
+    Cgroup(PathBuf),
+}
+
+impl Targets {
+    fn tgids(&self) -> Vec<i32> {
+        match self {
+            Targets::Pids(pids) => pids.clone(),
+            Targets::Cgroup(path) => {
+                procfs::read_cgroup_procs(path).expect("Can't read the cgroup")
+            }
+        }
+    }
 }
 
-fn inspect_process(pid: i32) -> Option<ProcessDataSnapshot> {
+//
+// Processes which exited are skipped, it's only an error if none of the
+// given pids is left. A cgroup is allowed to be empty.
+//
+fn inspect_processes(targets: &Targets) -> Option<ProcessDataSnapshot> {
     let mut ret = ProcessDataSnapshot {
-        pid: pid,
+        tgids: vec![],
         threads: HashMap::new(),
+        exited: HashMap::new(),
     };
 
-    for tid in procfs::read_proc_threads(pid).expect("Can't find the specified process") {
-        if let Some(td) = inspect_thread(pid, tid) {
-            ret.threads.insert(tid, td);
+    for tgid in targets.tgids() {
+        let tids = match procfs::read_proc_threads(tgid) {
+            Some(tids) => tids,
+            None => continue,
+        };
+
+        for tid in tids {
+            if let Some(td) = inspect_thread(tgid, tid) {
+                ret.threads.insert(tid, td);
+            }
         }
+        ret.tgids.push(tgid);
+    }
+
+    if ret.tgids.is_empty() && matches!(targets, Targets::Pids(_)) {
+        return None;
     }
 
     Some(ret)
@@ -85,51 +169,194 @@ fn system_load(p: &procfs::StatData, n: &procfs::StatData) -> u64 {
     total - idle
 }
 
-fn print_delta_procs(
-    table: &mut output::Table,
+// How threads are merged into rows
+enum GroupBy {
+    Comm,
+    Regex(Regex, String), // threads with matching names, the pattern as given
+}
+
+impl GroupBy {
+    fn new(s: &str) -> GroupBy {
+        if s == "comm" {
+            return GroupBy::Comm;
+        }
+
+        let re = Regex::new(&format!("^(?:{})$", s)).expect("Invalid regex specified");
+        GroupBy::Regex(re, s.to_string())
+    }
+
+    // The group of a thread and its name, threads not matching the regex
+    // aren't merged with any other
+    fn key(&self, pid: i32, comm: &str) -> (i32, String) {
+        match self {
+            GroupBy::Comm => (0, comm.to_string()),
+            GroupBy::Regex(re, pattern) if re.is_match(comm) => (0, pattern.clone()),
+            GroupBy::Regex(..) => (pid, comm.to_string()),
+        }
+    }
+}
+
+//
+// Merges per-thread values of the same group within a process. The first
+// column of grouped rows holds the number of merged threads instead of a pid.
+//
+fn group<T>(
+    group_by: &Option<GroupBy>,
+    items: Vec<(i32, i32, String, T)>,
+    merge: impl Fn(&mut T, &T),
+) -> Vec<(i64, i32, String, T)> {
+    let group_by = match group_by {
+        Some(group_by) => group_by,
+        None => {
+            return items
+                .into_iter()
+                .map(|(pid, tgid, comm, v)| (pid as i64, tgid, comm, v))
+                .collect()
+        }
+    };
+
+    let mut groups: HashMap<(i32, (i32, String)), (i64, T)> = HashMap::new();
+    for (pid, tgid, comm, v) in items {
+        match groups.entry((tgid, group_by.key(pid, &comm))) {
+            Entry::Occupied(mut e) => {
+                let (threads, sum) = e.get_mut();
+                *threads += 1;
+                merge(sum, &v);
+            }
+            Entry::Vacant(e) => {
+                e.insert((1, v));
+            }
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|((tgid, (_, name)), (threads, v))| (threads, tgid, name, v))
+        .collect()
+}
+
+fn label_groups(table: &mut output::Table, group_by: &Option<GroupBy>) {
+    if group_by.is_some() {
+        table.columns[0].title = "threads".to_string();
+    }
+}
+
+// The main table and how its rows are grouped
+struct View {
+    table: output::Table,
+    group_by: Option<GroupBy>,
+    topology: Option<topology::Topology>, // of the profiled machine, if known
+}
+
+fn thread_row(
+    id: i64,
+    tgid: i32,
+    comm: &str,
+    d: &ThreadDataSnapshot,
+    load: u64,
+) -> Vec<output::Data> {
+    let avg_slice = d.on_cpu.checked_div(d.slices).unwrap_or(0);
+
+    vec![
+        output::Data::Int(id),
+        output::Data::Int(tgid as i64),
+        output::Data::Text(comm.to_string()),
+        output::Data::Float(d.utime as f64 / load as f64 * 100.0),
+        output::Data::Float(d.stime as f64 / load as f64 * 100.0),
+        output::Data::UInt(d.on_cpu),
+        output::Data::UInt(d.waiting_for_cpu),
+        output::Data::UInt(d.slices),
+        output::Data::UInt(avg_slice),
+        output::Data::UInt(d.vctxsw),
+        output::Data::UInt(d.ivctxsw),
+    ]
+}
+
+//
+// Fills the main table with per-thread (or per-group) deltas and the totals
+// of the processes, returns the number of died and born threads
+//
+fn delta_procs(
+    view: &mut View,
     prev: &ProcessDataSnapshot,
     curr: &ProcessDataSnapshot,
     load: u64,
-) {
-    assert_eq!(prev.pid, curr.pid);
-
+) -> (usize, usize) {
     let p_threads: HashSet<_> = prev.threads.keys().cloned().collect();
     let c_threads: HashSet<_> = curr.threads.keys().cloned().collect();
-    let alive: HashSet<_> = p_threads.intersection(&c_threads).collect();
     let died: HashSet<_> = p_threads.difference(&c_threads).collect();
     let born: HashSet<_> = c_threads.difference(&p_threads).collect();
 
-    println!(
-        "{} threads, {} died, {} born",
-        c_threads.len(),
-        died.len(),
-        born.len()
-    );
+    // threads born during the interval are accounted from zero
+    let zero = ThreadDataSnapshot::default();
+    let mut deltas = vec![];
+    let mut total = ThreadDataSnapshot::default();
 
-    for pid in &alive {
-        let p = prev.threads.get(&pid).unwrap();
-        let c = curr.threads.get(&pid).unwrap();
+    for pid in &c_threads {
+        let p = prev.threads.get(pid).unwrap_or(&zero);
+        let c = curr.threads.get(pid).unwrap();
 
-        let on_cpu = c.on_cpu - p.on_cpu;
-        let slices = c.slices - p.slices;
-        let avg_slice = if slices > 0 { on_cpu / slices } else { 0 };
+        let d = ThreadDataSnapshot {
+            pid: c.pid,
+            tgid: c.tgid,
+            comm: c.comm.clone(),
+            utime: c.utime - p.utime,
+            stime: c.stime - p.stime,
+            vctxsw: c.vctxsw - p.vctxsw,
+            ivctxsw: c.ivctxsw - p.ivctxsw,
+            on_cpu: c.on_cpu - p.on_cpu,
+            waiting_for_cpu: c.waiting_for_cpu - p.waiting_for_cpu,
+            slices: c.slices - p.slices,
+            cpu: c.cpu,
+        };
+        total.merge(&d);
+        deltas.push((c.pid, c.tgid, c.comm.clone(), d));
+    }
 
-        table.add_row(vec![
-            output::Data::Int(p.pid as i64),
-            output::Data::Text(p.comm.clone()),
-            output::Data::Float((c.utime - p.utime) as f64 / load as f64 * 100.0),
-            output::Data::Float((c.stime - p.stime) as f64 / load as f64 * 100.0),
-            output::Data::UInt(on_cpu),
-            output::Data::UInt(c.waiting_for_cpu - p.waiting_for_cpu),
-            output::Data::UInt(slices),
-            output::Data::UInt(avg_slice),
-            output::Data::UInt(c.vctxsw - p.vctxsw),
-            output::Data::UInt(c.ivctxsw - p.ivctxsw),
-        ]);
+    // counters at exit may be a bit off from procfs, hence saturating
+    for (pid, c) in &curr.exited {
+        let p = prev.threads.get(pid).unwrap_or(&zero);
+
+        let d = ThreadDataSnapshot {
+            pid: c.pid,
+            tgid: c.tgid,
+            comm: c.comm.clone(),
+            utime: c.utime.saturating_sub(p.utime),
+            stime: c.stime.saturating_sub(p.stime),
+            vctxsw: c.vctxsw.saturating_sub(p.vctxsw),
+            ivctxsw: c.ivctxsw.saturating_sub(p.ivctxsw),
+            on_cpu: c.on_cpu.saturating_sub(p.on_cpu),
+            waiting_for_cpu: c.waiting_for_cpu.saturating_sub(p.waiting_for_cpu),
+            slices: c.slices.saturating_sub(p.slices),
+            cpu: p.cpu,
+        };
+        total.merge(&d);
+
+        // grouped rows are summed up with live threads of the same name
+        let comm = match view.group_by {
+            Some(_) => c.comm.clone(),
+            None => format!("{} (exited)", c.comm),
+        };
+        deltas.push((*pid, c.tgid, comm, d));
     }
 
-    println!("{}", table.display_table());
-    table.clear_data();
+    for (id, tgid, comm, d) in group(&view.group_by, deltas, ThreadDataSnapshot::merge) {
+        view.table.add_row(thread_row(id, tgid, &comm, &d, load));
+    }
+
+    // no pid for totals, nor a tgid if there are several processes
+    let threads = c_threads.len() + curr.exited.len();
+    let mut footer = thread_row(threads as i64, 0, "total", &total, load);
+    if view.group_by.is_none() {
+        footer[0] = output::Data::Text(String::new());
+    }
+    footer[1] = match curr.tgids[..] {
+        [tgid] => output::Data::Int(tgid as i64),
+        _ => output::Data::Text(String::new()),
+    };
+    view.table.footer = Some(footer);
+
+    (died.len(), born.len())
 }
 
 fn tgidpid_tgid(tgidpid: u64) -> i32 {
@@ -140,111 +367,143 @@ fn tgidpid_pid(tgidpid: u64) -> i32 {
     tgidpid as i32
 }
 
-fn print_top_events(map: &HashMap<i32, u64>, curr: &ProcessDataSnapshot) -> String {
-    let mut count_vec: Vec<_> = map.iter().collect();
-    count_vec.sort_by(|a, b| b.1.cmp(a.1));
+// Rows of the wakeup tables of a process, all of them are shown for a thread
+const TOP_EVENTS: usize = 20;
+
+fn top_events_table(
+    map: &HashMap<u64, u64>,
+    curr: &ProcessDataSnapshot,
+    top: Option<usize>,
+) -> output::Table {
     let unknown = "unknown".to_string();
 
-    let mut table = table![("pid", 8), ("comm", 16), ("wakeups", 10)];
-    table.sort_by = Some(2); // sort by events
+    let mut table = table![("pid", 8), ("tgid", 8), ("comm", 16), ("wakeups", 10)];
+    table.sort_by = Some(3); // sort by events
+    table.top = top;
 
-    let mut c = 0;
-    for i in count_vec {
-        if c > 20 {
-            break;
-        }
-        c += 1;
-        let pid = i.0;
-        let comm = match curr.threads.get(pid) {
+    for (tgidpid, count) in map {
+        let pid = tgidpid_pid(*tgidpid);
+        let comm = match curr.thread(pid) {
             Some(t) => &t.comm,
             None => &unknown,
         };
 
         table.add_row(vec![
-            output::Data::Int(*pid as i64),
+            output::Data::Int(pid as i64),
+            output::Data::Int(tgidpid_tgid(*tgidpid) as i64),
             output::Data::Text(comm.to_string()),
-            output::Data::UInt(*i.1),
+            output::Data::UInt(*count),
         ]);
     }
 
-    table.display_table()
+    table
 }
 
-fn print_2tables(title1: &str, table1: &str, title2: &str, table2: &str) {
+// Two tables side by side, followed by an empty line
+fn format_2tables(title1: &str, table1: &str, title2: &str, table2: &str) -> String {
     let mut l1 = table1.lines();
     let mut l2 = table2.lines();
+    let mut output = String::new();
 
     let width = table1.lines().nth(1).unwrap().len();
 
-    println!("{1:^0$}  {2:^0$}", width, title1, title2);
+    output.push_str(&format!("{1:^0$}  {2:^0$}\n", width, title1, title2));
     loop {
         let s1 = l1.next();
         let s2 = l2.next();
         if s1.is_some() || s2.is_some() {
-            println!(
-                "{1:^0$}  {2:^0$}",
+            output.push_str(&format!(
+                "{1:^0$}  {2:^0$}\n",
                 width,
                 s1.unwrap_or(""),
                 s2.unwrap_or("")
-            );
+            ));
         } else {
             break;
         }
     }
-    println!("");
+    output.push('\n');
+
+    output
+}
+
+fn print_2tables(title1: &str, table1: &str, title2: &str, table2: &str) {
+    print!("{}", format_2tables(title1, table1, title2, table2));
 }
 
-fn print_wakeups(wakeups: &bpf::Wakeups, curr: &ProcessDataSnapshot) {
-    let mut inputs: HashMap<i32, u64> = HashMap::new();
-    let mut outputs: HashMap<i32, u64> = HashMap::new();
-    let mut wakees: HashMap<i32, u64> = HashMap::new();
-    let mut wakers: HashMap<i32, u64> = HashMap::new();
+struct WakeupTables {
+    inputs: output::Table,
+    outputs: output::Table,
+    wakers: output::Table,
+    wakees: output::Table,
+}
 
-    let tgid = curr.pid;
+fn wakeup_tables(wakeups: &bpf::Wakeups, curr: &ProcessDataSnapshot) -> WakeupTables {
+    let mut inputs: HashMap<u64, u64> = HashMap::new();
+    let mut outputs: HashMap<u64, u64> = HashMap::new();
+    let mut wakees: HashMap<u64, u64> = HashMap::new();
+    let mut wakers: HashMap<u64, u64> = HashMap::new();
 
     for item in wakeups {
         let src = item.0 .0;
         let tgt = item.0 .1;
         let count = item.1;
-        let tgid1 = tgidpid_tgid(src);
-        let pid1 = tgidpid_pid(src);
-        let tgid2 = tgidpid_tgid(tgt);
-        let pid2 = tgidpid_pid(tgt);
 
-        if tgid1 != tgid {
-            assert_eq!(tgid2, tgid);
+        // wakeups between profiled processes are internal ones; tasks
+        // which left a cgroup during the interval might be neither
+        let src_in = curr.has(tgidpid_tgid(src));
+        let tgt_in = curr.has(tgidpid_tgid(tgt));
 
-            let entry = inputs.entry(pid2).or_insert(0);
+        if !src_in && tgt_in {
+            let entry = inputs.entry(tgt).or_insert(0);
             *entry += count;
         }
 
-        if tgid2 != tgid {
-            assert_eq!(tgid1, tgid);
-
-            let entry = outputs.entry(pid1).or_insert(0);
+        if src_in && !tgt_in {
+            let entry = outputs.entry(src).or_insert(0);
             *entry += count;
         }
 
-        if tgid1 == tgid && tgid2 == tgid {
-            let entry = wakers.entry(pid1).or_insert(0);
+        if src_in && tgt_in {
+            let entry = wakers.entry(src).or_insert(0);
             *entry += count;
-            let entry = wakees.entry(pid2).or_insert(0);
+            let entry = wakees.entry(tgt).or_insert(0);
             *entry += count;
         }
     }
 
-    let inputs = print_top_events(&inputs, &curr);
-    let outputs = print_top_events(&outputs, &curr);
-    let wakers = print_top_events(&wakers, &curr);
-    let wakees = print_top_events(&wakees, &curr);
+    WakeupTables {
+        inputs: top_events_table(&inputs, curr, Some(TOP_EVENTS)),
+        outputs: top_events_table(&outputs, curr, Some(TOP_EVENTS)),
+        wakers: top_events_table(&wakers, curr, Some(TOP_EVENTS)),
+        wakees: top_events_table(&wakees, curr, Some(TOP_EVENTS)),
+    }
+}
 
-    print_2tables("top inputs", &inputs, "top outputs", &outputs);
-    print_2tables("top wakees", &wakees, "top wakers", &wakers);
+// Per-thread values along with tgids and thread names, ready to be grouped
+fn thread_items<T: Clone>(
+    map: &HashMap<i32, T>,
+    curr: &ProcessDataSnapshot,
+) -> Vec<(i32, i32, String, T)> {
+    map.iter()
+        .map(|(pid, v)| {
+            let (tgid, comm) = match curr.thread(*pid) {
+                Some(t) => (t.tgid, t.comm.clone()),
+                None => (0, "unknown".to_string()),
+            };
+            (*pid, tgid, comm, v.clone())
+        })
+        .collect()
 }
 
-fn print_slices(slices: &mut bpf::Slices, curr: &ProcessDataSnapshot) {
+fn slices_table(
+    slices: &bpf::Hists,
+    curr: &ProcessDataSnapshot,
+    group_by: &Option<GroupBy>,
+) -> output::Table {
     let mut table = table![
         ("pid", 8),
+        ("tgid", 8),
         ("comm", 16),
         ("slices", 10),
         ("min", 6),
@@ -256,65 +515,1279 @@ fn print_slices(slices: &mut bpf::Slices, curr: &ProcessDataSnapshot) {
         ("max", 6)
     ];
 
-    table.sort_by = Some(2); // sort by slices
+    table.sort_by = Some(3); // sort by slices
+    label_groups(&mut table, group_by);
 
-    for (pid, vec) in slices {
-        let unknown = "unknown".to_string();
-        let comm = match curr.threads.get(pid) {
-            Some(t) => &t.comm,
-            None => &unknown,
+    for (id, tgid, comm, hist) in
+        group(group_by, thread_items(slices, curr), hist::Histogram::merge)
+    {
+        table.add_row(vec![
+            output::Data::Int(id),
+            output::Data::Int(tgid as i64),
+            output::Data::Text(comm.to_string()),
+            output::Data::UInt(hist.count()),
+            output::Data::UInt(hist.min()),
+            output::Data::UInt(hist.percentile(5)),
+            output::Data::UInt(hist.percentile(25)),
+            output::Data::UInt(hist.percentile(50)),
+            output::Data::UInt(hist.percentile(75)),
+            output::Data::UInt(hist.percentile(95)),
+            output::Data::UInt(hist.max()),
+        ]);
+    }
+
+    table
+}
+
+fn runq_table(
+    runq: &bpf::Hists,
+    curr: &ProcessDataSnapshot,
+    group_by: &Option<GroupBy>,
+) -> output::Table {
+    let mut table = table![
+        ("pid", 8),
+        ("tgid", 8),
+        ("comm", 16),
+        ("runs", 10),
+        ("p50", 6),
+        ("p90", 6),
+        ("p99", 6),
+        ("max", 6)
+    ];
+
+    table.sort_by = Some(6); // sort by p99
+    label_groups(&mut table, group_by);
+
+    for (id, tgid, comm, hist) in group(group_by, thread_items(runq, curr), hist::Histogram::merge)
+    {
+        table.add_row(vec![
+            output::Data::Int(id),
+            output::Data::Int(tgid as i64),
+            output::Data::Text(comm.to_string()),
+            output::Data::UInt(hist.count()),
+            output::Data::UInt(hist.percentile(50)),
+            output::Data::UInt(hist.percentile(90)),
+            output::Data::UInt(hist.percentile(99)),
+            output::Data::UInt(hist.max()),
+        ]);
+    }
+
+    table
+}
+
+fn offcpu_table(
+    offcpu: &HashMap<i32, bpf::OffCpu>,
+    curr: &ProcessDataSnapshot,
+    group_by: &Option<GroupBy>,
+) -> output::Table {
+    let mut table = table![
+        ("pid", 8),
+        ("tgid", 8),
+        ("comm", 16),
+        ("off_cpu", 10),
+        ("preempted", 10),
+        ("preempt_us", 10),
+        ("sleep", 10),
+        ("sleep_us", 10),
+        ("dsleep", 10),
+        ("dsleep_us", 10),
+        ("other", 10),
+        ("other_us", 10)
+    ];
+
+    table.sort_by = Some(3); // sort by off_cpu
+    label_groups(&mut table, group_by);
+
+    for (id, tgid, comm, oc) in group(group_by, thread_items(offcpu, curr), bpf::OffCpu::merge) {
+        let mut row = vec![
+            output::Data::Int(id),
+            output::Data::Int(tgid as i64),
+            output::Data::Text(comm.to_string()),
+            output::Data::UInt(oc.time.iter().sum()),
+        ];
+        for i in 0..bpf::OFFCPU_STATES.len() {
+            row.push(output::Data::UInt(oc.count[i]));
+            row.push(output::Data::UInt(oc.time[i]));
+        }
+
+        table.add_row(row);
+    }
+
+    table
+}
+
+// Threads of other processes are looked up in procfs
+fn thread_comm(curr: &ProcessDataSnapshot, tgidpid: u64) -> String {
+    let pid = tgidpid_pid(tgidpid);
+
+    match curr.thread(pid) {
+        Some(t) => t.comm.clone(),
+        None => procfs::read_proc_status(pid).map_or("unknown".to_string(), |s| s.name),
+    }
+}
+
+// The most frequent wakeup chains and how long each hop took on average
+fn chains_table(chains: &bpf::Chains, curr: &ProcessDataSnapshot) -> output::Table {
+    let mut table = table![("count", 8), ("avg_us", 10), ("hops_us", 24), ("chain", 64)];
+    table.sort_by = Some(0); // sort by count
+    table.top = Some(TOP_EVENTS);
+
+    for (pids, stats) in chains {
+        let avg_us = |ns: u64| ns / stats.count.max(1) / 1000;
+        let hops: Vec<_> = stats
+            .hop_ns
+            .iter()
+            .map(|ns| avg_us(*ns).to_string())
+            .collect();
+        let chain: Vec<_> = pids
+            .iter()
+            .map(|pid| format!("{} ({})", pid, thread_comm(curr, *pid as u64)))
+            .collect();
+
+        table.add_row(vec![
+            output::Data::UInt(stats.count),
+            output::Data::UInt(avg_us(stats.hop_ns.iter().sum())),
+            output::Data::Text(hops.join(" + ")),
+            output::Data::Text(chain.join(" -> ")),
+        ]);
+    }
+
+    table
+}
+
+//
+// Migrations and wakeups of threads by how far they moved: to another cpu,
+// out of the last level cache or to another numa node. The last cpu comes
+// from procfs, so it's there even for threads which didn't run.
+//
+// Without the topology, only the numbers of migrations and wakeups are known
+fn placement_table(
+    data: &bpf::Interval,
+    curr: &ProcessDataSnapshot,
+    group_by: &Option<GroupBy>,
+    topo: Option<&topology::Topology>,
+) -> output::Table {
+    let mut table = table![
+        ("pid", 8),
+        ("tgid", 8),
+        ("comm", 16),
+        ("migrations", 10),
+        ("x_llc", 10),
+        ("x_node", 10),
+        ("wakeups", 10),
+        ("w_x_cpu%", 8),
+        ("w_x_llc%", 8),
+        ("w_x_node%", 9),
+        ("last_cpu", 8),
+        ("top_cpus", 24)
+    ];
+
+    table.sort_by = Some(3); // sort by migrations
+    label_groups(&mut table, group_by);
+
+    let unknown = topology::Topology::default();
+    let mut placements = topology::placements(data, topo.unwrap_or(&unknown));
+    for pid in curr.threads.keys() {
+        placements.entry(*pid).or_default();
+    }
+
+    for (id, tgid, comm, p) in group(
+        group_by,
+        thread_items(&placements, curr),
+        topology::Placement::merge,
+    ) {
+        let wakeups: u64 = p.wakeups.iter().sum();
+        let share = |n: u64| n as f64 / wakeups.max(1) as f64 * 100.0;
+        let on_cpu: u64 = p.cpus.values().sum();
+        let top_cpus: Vec<_> = p
+            .top_cpus()
+            .iter()
+            .take(3)
+            .map(|(cpu, us)| format!("{}:{:.0}%", cpu, *us as f64 / on_cpu.max(1) as f64 * 100.0))
+            .collect();
+        let last_cpu = match (group_by, curr.threads.get(&(id as i32))) {
+            (None, Some(t)) => output::Data::UInt(t.cpu as u64),
+            _ => output::Data::Text(String::new()),
+        };
+        let far = |d: output::Data| match topo {
+            Some(_) => d,
+            None => output::Data::Text(String::new()),
         };
-        vec.sort_unstable();
 
-        let min = vec.first().unwrap();
-        let p5 = vec.get(vec.len() / 20).unwrap();
-        let p25 = vec.get(vec.len() / 4).unwrap();
-        let p50 = vec.get(vec.len() / 2).unwrap();
-        let p75 = vec.get(vec.len() / 4 * 3).unwrap();
-        let p95 = vec.get(vec.len() / 20 * 19).unwrap();
-        let max = vec.last().unwrap();
+        table.add_row(vec![
+            output::Data::Int(id),
+            output::Data::Int(tgid as i64),
+            output::Data::Text(comm.to_string()),
+            output::Data::UInt(p.migrations[1..].iter().sum()),
+            far(output::Data::UInt(p.migrations[2..].iter().sum())),
+            far(output::Data::UInt(p.migrations[3])),
+            output::Data::UInt(wakeups),
+            output::Data::Float(share(p.wakeups[1..].iter().sum())),
+            far(output::Data::Float(share(p.wakeups[2..].iter().sum()))),
+            far(output::Data::Float(share(p.wakeups[3]))),
+            last_cpu,
+            output::Data::Text(top_cpus.join(" ")),
+        ]);
+    }
 
+    table
+}
+
+//
+// Every thread created or exited during the interval, even if it didn't
+// live long enough to be seen in procfs.
+//
+fn lifecycle_table(lifecycle: &bpf::Lifecycle) -> output::Table {
+    let mut table = table![
+        ("pid", 8),
+        ("tgid", 8),
+        ("comm", 16),
+        ("parent", 8),
+        ("born", 12),
+        ("exited", 12),
+        ("lifetime_us", 11),
+        ("on_cpu_us", 10)
+    ];
+    table.sort_by = None; // by creation time, as added
+
+    let text = |s: Option<String>| output::Data::Text(s.unwrap_or_default());
+    let time = |ns: Option<u64>| {
+        text(ns.map(|ns| {
+            chrono::DateTime::<chrono::Local>::from(UNIX_EPOCH + Duration::from_nanos(ns))
+                .format("%H:%M:%S%.3f")
+                .to_string()
+        }))
+    };
+    let us = |us: Option<u64>| match us {
+        Some(us) => output::Data::UInt(us),
+        None => output::Data::Text(String::new()),
+    };
+
+    let mut threads: Vec<_> = lifecycle.iter().collect();
+    threads.sort_by_key(|(pid, life)| (life.born_ns, life.exited_ns, **pid));
+
+    for (pid, life) in threads {
         table.add_row(vec![
             output::Data::Int(*pid as i64),
-            output::Data::Text(comm.to_string()),
-            output::Data::UInt(vec.len() as u64),
-            output::Data::UInt(*min),
-            output::Data::UInt(*p5),
-            output::Data::UInt(*p25),
-            output::Data::UInt(*p50),
-            output::Data::UInt(*p75),
-            output::Data::UInt(*p95),
-            output::Data::UInt(*max),
+            output::Data::Int(life.tgid as i64),
+            output::Data::Text(life.comm.clone()),
+            text(life.parent.map(|p| p.to_string())),
+            time(life.born_ns),
+            time(life.exited_ns),
+            us(life.lifetime_us),
+            us(life.on_cpu_us),
         ]);
     }
 
-    println!("{}", table.display_table());
+    table
+}
+
+// Placement is only traced with --placement
+fn has_placement(data: &bpf::Interval) -> bool {
+    !data.migrations.is_empty() || !data.wakeup_cpus.is_empty()
+}
+
+// Symbolized waker stacks of a wakeup edge
+struct EdgeStacks {
+    waker: (i32, String),
+    wakee: (i32, String),
+    wakeups: u64,
+    stacks: Vec<(u64, Vec<String>, Vec<String>)>, // count, kernel and user frames
+}
+
+fn edge_stacks(
+    (src, tgt): (u64, u64),
+    wakeups: u64,
+    stacks: &bpf::WakerStacks,
+    curr: &ProcessDataSnapshot,
+    syms: &mut syms::Symbolizer,
+) -> EdgeStacks {
+    let mut top: Vec<_> = stacks
+        .iter()
+        .filter(|((edge, _), _)| *edge == (src, tgt))
+        .map(|((_, stack), count)| (stack, *count))
+        .collect();
+    top.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    EdgeStacks {
+        waker: (tgidpid_pid(src), thread_comm(curr, src)),
+        wakee: (tgidpid_pid(tgt), thread_comm(curr, tgt)),
+        wakeups,
+        stacks: top
+            .into_iter()
+            .take(3)
+            .map(|(stack, count)| {
+                let kernel = stack.kernel.iter().map(|a| syms.kernel(*a)).collect();
+                let user = stack
+                    .user
+                    .iter()
+                    .map(|a| syms.user(tgidpid_tgid(src), *a))
+                    .collect();
+                (count, kernel, user)
+            })
+            .collect(),
+    }
+}
+
+// Top waker stacks of the busiest input and output edges
+fn top_waker_stacks(
+    stacks: &bpf::WakerStacks,
+    curr: &ProcessDataSnapshot,
+    syms: &mut syms::Symbolizer,
+) -> (Vec<EdgeStacks>, Vec<EdgeStacks>) {
+    let mut edges: HashMap<(u64, u64), u64> = HashMap::new();
+    for ((edge, _), count) in stacks {
+        *edges.entry(*edge).or_insert(0) += count;
+    }
+
+    let mut edges: Vec<_> = edges.into_iter().collect();
+    edges.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    let (inputs, outputs): (Vec<_>, Vec<_>) = edges
+        .into_iter()
+        .filter(|((src, tgt), _)| curr.has(tgidpid_tgid(*src)) != curr.has(tgidpid_tgid(*tgt)))
+        .partition(|((src, _), _)| !curr.has(tgidpid_tgid(*src)));
+
+    let mut top = |edges: Vec<_>| {
+        edges
+            .into_iter()
+            .take(5)
+            .map(|(edge, wakeups)| edge_stacks(edge, wakeups, stacks, curr, syms))
+            .collect()
+    };
+
+    (top(inputs), top(outputs))
+}
+
+fn print_edge_stacks(title: &str, edges: &[EdgeStacks]) {
+    if edges.is_empty() {
+        return;
+    }
+
+    println!("{}", title);
+    for edge in edges {
+        println!(
+            "{} ({}) -> {} ({}): {} wakeups",
+            edge.waker.0, edge.waker.1, edge.wakee.0, edge.wakee.1, edge.wakeups
+        );
+
+        for (count, kernel, user) in &edge.stacks {
+            println!("    {}", count);
+            for frame in kernel {
+                println!("        {}", frame);
+            }
+            if !user.is_empty() {
+                println!("        --");
+            }
+            for frame in user {
+                println!("        {}", frame);
+            }
+        }
+    }
+    println!();
+}
+
+fn edge_stacks_json(edges: &[EdgeStacks]) -> serde_json::Value {
+    edges
+        .iter()
+        .map(|edge| {
+            serde_json::json!({
+                "waker": edge.waker.0,
+                "waker_comm": edge.waker.1,
+                "wakee": edge.wakee.0,
+                "wakee_comm": edge.wakee.1,
+                "wakeups": edge.wakeups,
+                "stacks": edge.stacks.iter().map(|(count, kernel, user)| serde_json::json!({
+                    "count": count,
+                    "kernel": kernel,
+                    "user": user,
+                })).collect::<Vec<_>>(),
+            })
+        })
+        .collect()
+}
+
+// Prints every report section of an interval between the prev and curr snapshots
+fn print_interval(
+    view: &mut View,
+    prev: &ProcessDataSnapshot,
+    curr: &ProcessDataSnapshot,
+    load: u64,
+    data: &bpf::Interval,
+    syms: Option<&mut syms::Symbolizer>,
+) {
+    let (died, born) = delta_procs(view, prev, curr, load);
+    println!(
+        "{} threads, {} died, {} born",
+        curr.threads.len(),
+        died,
+        born
+    );
+    println!("{}", view.table.display_table());
+    view.table.clear_data();
+
+    let mut wakeups = wakeup_tables(&data.wakeups, curr);
+    print_2tables(
+        "top inputs",
+        &wakeups.inputs.display_table(),
+        "top outputs",
+        &wakeups.outputs.display_table(),
+    );
+    print_2tables(
+        "top wakees",
+        &wakeups.wakees.display_table(),
+        "top wakers",
+        &wakeups.wakers.display_table(),
+    );
+
+    if let Some(syms) = syms {
+        let (inputs, outputs) = top_waker_stacks(&data.waker_stacks, curr, syms);
+        print_edge_stacks("top input waker stacks", &inputs);
+        print_edge_stacks("top output waker stacks", &outputs);
+    }
+
+    if !data.chains.is_empty() {
+        println!("top wakeup chains");
+        println!("{}", chains_table(&data.chains, curr).display_table());
+    }
+
+    let group_by = &view.group_by;
+    if has_placement(data) {
+        let topo = view.topology.as_ref();
+        println!(
+            "{}",
+            placement_table(data, curr, group_by, topo).display_table()
+        );
+    }
+    if !data.cpu_shares.is_empty() {
+        println!("{}", cpus::cpus_table(data).display_table());
+    }
+    if !data.lifecycle.is_empty() {
+        println!("threads created and exited");
+        println!("{}", lifecycle_table(&data.lifecycle).display_table());
+    }
+    if !data.preemptions.is_empty() {
+        println!("top preemptors");
+        println!("{}", cpus::preemptors_table(data, curr).display_table());
+    }
+    println!(
+        "{}",
+        slices_table(&data.slices, curr, group_by).display_table()
+    );
+    println!("{}", runq_table(&data.runq, curr, group_by).display_table());
+    println!(
+        "{}",
+        offcpu_table(&data.offcpu, curr, group_by).display_table()
+    );
+}
+
+// Same as print_interval(), but as a single JSON document
+fn interval_json(
+    view: &mut View,
+    time: &str,
+    prev: &ProcessDataSnapshot,
+    curr: &ProcessDataSnapshot,
+    load: u64,
+    data: &bpf::Interval,
+    syms: Option<&mut syms::Symbolizer>,
+) -> serde_json::Value {
+    let (died, born) = delta_procs(view, prev, curr, load);
+    let threads = view.table.json_rows();
+    let total = view.table.json_footer();
+    view.table.clear_data();
+
+    let mut wakeups = wakeup_tables(&data.wakeups, curr);
+    let mut doc = serde_json::json!({
+        "time": time,
+        "tgids": curr.tgids,
+        "nr_threads": curr.threads.len(),
+        "died": died,
+        "born": born,
+        "threads": threads,
+        "total": total,
+        "inputs": wakeups.inputs.json_rows(),
+        "outputs": wakeups.outputs.json_rows(),
+        "wakers": wakeups.wakers.json_rows(),
+        "wakees": wakeups.wakees.json_rows(),
+        "slices": slices_table(&data.slices, curr, &view.group_by).json_rows(),
+        "runq": runq_table(&data.runq, curr, &view.group_by).json_rows(),
+        "offcpu": offcpu_table(&data.offcpu, curr, &view.group_by).json_rows(),
+        "chains": chains_table(&data.chains, curr).json_rows(),
+        "preemptors": cpus::preemptors_table(data, curr).json_rows(),
+    });
+
+    if has_placement(data) {
+        doc["placement"] =
+            placement_table(data, curr, &view.group_by, view.topology.as_ref()).json_rows();
+    }
+
+    if !data.cpu_shares.is_empty() {
+        doc["cpus"] = cpus::cpus_table(data).json_rows();
+    }
+
+    if !data.lifecycle.is_empty() {
+        doc["lifecycle"] = lifecycle_table(&data.lifecycle).json_rows();
+    }
+
+    if let Some(syms) = syms {
+        let (inputs, outputs) = top_waker_stacks(&data.waker_stacks, curr, syms);
+        doc["waker_stacks"] = serde_json::json!({
+            "inputs": edge_stacks_json(&inputs),
+            "outputs": edge_stacks_json(&outputs),
+        });
+    }
+
+    doc
+}
+
+// Folded stacks for flamegraph.pl/inferno: comm;user frames;kernel frames us
+fn write_folded(
+    out: &mut impl Write,
+    stacks: &bpf::Stacks,
+    curr: &ProcessDataSnapshot,
+    syms: &mut syms::Symbolizer,
+) -> std::io::Result<()> {
+    for ((pid, stack), us) in stacks {
+        // /proc/<tid>/maps of a thread is the same as of its process
+        let (tgid, comm) = match curr.thread(*pid) {
+            Some(t) => (t.tgid, t.comm.clone()),
+            None => (*pid, "unknown".to_string()),
+        };
+        let mut frames = vec![comm];
+
+        for addr in stack.user.iter().rev() {
+            frames.push(syms.user(tgid, *addr));
+        }
+        for addr in stack.kernel.iter().rev() {
+            frames.push(syms.kernel(*addr));
+        }
+
+        writeln!(out, "{} {}", frames.join(";"), us)?;
+    }
+
+    out.flush()
+}
+
+fn create_csv(path: &Path, header: &str) -> BufWriter<File> {
+    let mut out = BufWriter::new(File::create(path).expect("Can't create the CSV file"));
+    writeln!(out, "{}", header).expect("Can't write the CSV file");
+
+    out
+}
+
+fn write_wakeups_csv(
+    out: &mut impl Write,
+    time: &str,
+    wakeups: &bpf::Wakeups,
+    curr: &ProcessDataSnapshot,
+) -> std::io::Result<()> {
+    let tables = wakeup_tables(wakeups, curr);
+
+    for (name, mut table) in [
+        ("inputs", tables.inputs),
+        ("outputs", tables.outputs),
+        ("wakers", tables.wakers),
+        ("wakees", tables.wakees),
+    ] {
+        out.write_all(table.csv_rows(&[time, name]).as_bytes())?;
+    }
+
+    out.flush()
+}
+
+fn write_slices_csv(
+    out: &mut impl Write,
+    time: &str,
+    slices: &bpf::Hists,
+    curr: &ProcessDataSnapshot,
+    group_by: &Option<GroupBy>,
+) -> std::io::Result<()> {
+    let mut table = slices_table(slices, curr, group_by);
+    out.write_all(table.csv_rows(&[time]).as_bytes())?;
+
+    out.flush()
+}
+
+// Threads are labelled by name only with by_comm, so series survive restarts
+fn thread_labels(prefix: &str, pid: i32, comm: &str, by_comm: bool) -> String {
+    let comm = metrics::label(&format!("{}comm", prefix), comm);
+
+    if by_comm {
+        comm
+    } else {
+        let pid = metrics::label(&format!("{}pid", prefix), &pid.to_string());
+        format!("{},{}", pid, comm)
+    }
+}
+
+fn update_metrics(
+    metrics: &mut metrics::Metrics,
+    prev: &ProcessDataSnapshot,
+    curr: &ProcessDataSnapshot,
+    data: &bpf::Interval,
+    by_comm: bool,
+) {
+    let zero = ThreadDataSnapshot::default();
+
+    // threads which exited are counted up to their exit
+    for (pid, c) in curr.threads.iter().chain(&curr.exited) {
+        let p = prev.threads.get(pid).unwrap_or(&zero);
+        let labels = thread_labels("", *pid, &c.comm, by_comm);
+
+        for (name, help, v) in [
+            (
+                "mole_on_cpu_microseconds_total",
+                "Time spent on CPU.",
+                c.on_cpu.saturating_sub(p.on_cpu),
+            ),
+            (
+                "mole_wait_microseconds_total",
+                "Time spent runnable waiting for a CPU.",
+                c.waiting_for_cpu.saturating_sub(p.waiting_for_cpu),
+            ),
+            (
+                "mole_slices_total",
+                "Number of times the thread got on a CPU.",
+                c.slices.saturating_sub(p.slices),
+            ),
+            (
+                "mole_voluntary_switches_total",
+                "Number of voluntary context switches.",
+                c.vctxsw.saturating_sub(p.vctxsw),
+            ),
+            (
+                "mole_involuntary_switches_total",
+                "Number of involuntary context switches.",
+                c.ivctxsw.saturating_sub(p.ivctxsw),
+            ),
+        ] {
+            metrics.add_counter(name, help, labels.clone(), v);
+        }
+    }
+
+    for ((src, tgt), count) in &data.wakeups {
+        let waker = thread_labels(
+            "waker_",
+            tgidpid_pid(*src),
+            &thread_comm(curr, *src),
+            by_comm,
+        );
+        let wakee = thread_labels(
+            "wakee_",
+            tgidpid_pid(*tgt),
+            &thread_comm(curr, *tgt),
+            by_comm,
+        );
+
+        metrics.add_counter(
+            "mole_wakeups_total",
+            "Number of wakeups from waker to wakee thread.",
+            format!("{},{}", waker, wakee),
+            *count,
+        );
+    }
+
+    for (pid, hist) in &data.slices {
+        let comm = thread_comm(curr, *pid as u64);
+
+        metrics.add_hist(
+            "mole_slice_duration_microseconds",
+            "Durations of on-CPU slices.",
+            thread_labels("", *pid, &comm, by_comm),
+            hist,
+        );
+    }
+}
+
+fn exited_thread(pid: i32, exit: &bpf::Exit) -> ThreadDataSnapshot {
+    ThreadDataSnapshot {
+        pid,
+        tgid: exit.tgid,
+        comm: exit.comm.clone(),
+        utime: exit.utime,
+        stime: exit.stime,
+        vctxsw: exit.vctxsw,
+        ivctxsw: exit.ivctxsw,
+        on_cpu: exit.on_cpu,
+        waiting_for_cpu: exit.waiting_for_cpu,
+        slices: exit.slices,
+        cpu: 0,
+    }
+}
+
+// What mole saw during an interval, also a line of a recording
+#[derive(Serialize, Deserialize)]
+struct Record {
+    time: String,
+    load: u64,
+    snapshot: ProcessDataSnapshot, // at the end of the interval
+    data: bpf::Interval,
+    comms: HashMap<i32, String>, // threads of other processes seen in wakeups
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    topology: Option<topology::Topology>, // in the first record only
+}
+
+impl Record {
+    // The first record of a recording, only with the initial snapshot
+    fn first(snapshot: ProcessDataSnapshot, topology: Option<topology::Topology>) -> Record {
+        Record {
+            time: chrono::Local::now().to_rfc3339(),
+            load: 0,
+            snapshot,
+            data: bpf::Interval::default(),
+            comms: HashMap::new(),
+            topology,
+        }
+    }
+
+    //
+    // The snapshot is taken before the data is drained, so threads which exit
+    // in between are seen both in the snapshot and in the exits. Counters at
+    // exit are the later ones then.
+    //
+    fn new(mut snapshot: ProcessDataSnapshot, load: u64, data: bpf::Interval) -> Record {
+        for (pid, exit) in &data.exits {
+            // an exited main thread stays in procfs until the whole process exits
+            if *pid == exit.tgid && snapshot.threads.contains_key(pid) {
+                continue;
+            }
+            snapshot.threads.remove(pid);
+            snapshot.exited.insert(*pid, exited_thread(*pid, exit));
+        }
+
+        let mut comms = HashMap::new();
+        for (src, tgt) in data.wakeups.keys() {
+            for tgidpid in &[*src, *tgt] {
+                if !snapshot.has(tgidpid_tgid(*tgidpid)) {
+                    comms
+                        .entry(tgidpid_pid(*tgidpid))
+                        .or_insert_with(|| thread_comm(&snapshot, *tgidpid));
+                }
+            }
+        }
+
+        Record {
+            time: chrono::Local::now().to_rfc3339(),
+            load,
+            snapshot,
+            data,
+            comms,
+            topology: None,
+        }
+    }
+}
+
+fn show_record(
+    format: output::Format,
+    view: &mut View,
+    prev: &ProcessDataSnapshot,
+    rec: &Record,
+    syms: Option<&mut syms::Symbolizer>,
+) {
+    let curr = &rec.snapshot;
+
+    match format {
+        output::Format::Text => print_interval(view, prev, curr, rec.load, &rec.data, syms),
+        output::Format::Json => println!(
+            "{}",
+            interval_json(view, &rec.time, prev, curr, rec.load, &rec.data, syms)
+        ),
+        output::Format::Csv => {
+            delta_procs(view, prev, curr, rec.load);
+            print!("{}", view.table.csv_rows(&[&rec.time]));
+            view.table.clear_data();
+        }
+    }
+}
+
+fn write_record(out: &mut impl Write, rec: &Record) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut *out, rec)?;
+    writeln!(out)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+fn read_records(path: &Path) -> impl Iterator<Item = Record> {
+    let file = BufReader::new(File::open(path).expect("Can't open the recording"));
+
+    file.lines().map(|line| {
+        let line = line.expect("Can't read the recording");
+        serde_json::from_str(&line).expect("Corrupted recording")
+    })
+}
+
+// The first record of a file only holds the initial snapshot
+fn report(
+    path: &Path,
+    format: output::Format,
+    view: &mut View,
+    tid: Option<i32>,
+    wakeup_graph: Option<(&Path, u64)>,
+) {
+    let mut prev: Option<ProcessDataSnapshot> = None;
+    let mut graph = graph::Graph::default();
+
+    if format == output::Format::Csv {
+        println!("time,{}", view.table.csv_header());
+    }
+
+    for rec in read_records(path) {
+        if prev.is_none() {
+            view.topology = rec.topology.clone();
+        }
+        match (&prev, tid) {
+            (Some(prev), Some(tid)) => focus::show(format, tid, prev, &rec),
+            (Some(prev), None) => show_record(format, view, prev, &rec, None),
+            (None, _) => (),
+        }
+        graph.add(&rec);
+        prev = Some(rec.snapshot);
+    }
+
+    if let Some((path, min)) = wakeup_graph {
+        graph
+            .write(path, min)
+            .expect("Can't write the wakeup graph");
+    }
+}
+
+//
+// Everything seen while profiling a launched command, printed once it exits
+//
+struct Summary {
+    first_stat: procfs::StatData,
+    first: ProcessDataSnapshot,
+    last: ProcessDataSnapshot, // last seen state of every thread, dead or alive
+    data: bpf::Interval,
+}
+
+impl Summary {
+    fn new(targets: &Targets) -> Summary {
+        let first = inspect_processes(targets).expect("Can't find the process");
+
+        Summary {
+            first_stat: procfs::read_stat(),
+            last: ProcessDataSnapshot {
+                tgids: first.tgids.clone(),
+                threads: HashMap::new(),
+                exited: HashMap::new(),
+            },
+            first,
+            data: bpf::Interval::default(),
+        }
+    }
+
+    fn add(&mut self, curr: &ProcessDataSnapshot, data: &bpf::Interval) {
+        for (tid, td) in &curr.threads {
+            self.last.threads.insert(*tid, td.clone());
+        }
+        for (tid, td) in &curr.exited {
+            self.last.threads.remove(tid);
+            self.last.exited.insert(*tid, td.clone());
+        }
+
+        self.data.merge(data);
+    }
+
+    fn print(
+        &self,
+        view: &mut View,
+        format: output::Format,
+        syms: Option<&mut syms::Symbolizer>,
+        status: i32,
+    ) {
+        let load = system_load(&self.first_stat, &procfs::read_stat());
+
+        match format {
+            output::Format::Text => {
+                println!("Command exited with status {}, summary:", status);
+                print_interval(view, &self.first, &self.last, load, &self.data, syms);
+            }
+            output::Format::Json => {
+                let time = chrono::Local::now().to_rfc3339();
+                let mut doc =
+                    interval_json(view, &time, &self.first, &self.last, load, &self.data, syms);
+                doc["exit_status"] = serde_json::json!(status);
+                println!("{}", doc);
+            }
+            // the rows of every interval already cover the whole run
+            output::Format::Csv => (),
+        }
+    }
+}
+
+// Where the data of every interval goes, depending on the mode
+trait Sink {
+    // Waits for the end of the interval if there's anything to do meanwhile,
+    // false to stop
+    fn wait(&mut self, _deadline: Instant) -> bool {
+        true
+    }
+
+    fn add(&mut self, prev: &ProcessDataSnapshot, rec: Record);
+
+    // The launched command exited with the status
+    fn finish(&mut self, _status: i32) {}
+}
+
+//
+// Drains the collector every interval and passes the data to the sink, until
+// the sink stops or the launched command exits. The wakeup graph is written
+// in every mode.
+//
+fn collect(
+    collector: &mut bpf::Collector,
+    targets: &Targets,
+    (mut prev_stat, mut prev): (procfs::StatData, ProcessDataSnapshot),
+    interval: Duration,
+    child: Option<&launch::Child>,
+    wakeup_graph: Option<(&Path, u64)>,
+    sink: &mut dyn Sink,
+) {
+    let mut graph = graph::Graph::default();
+
+    loop {
+        let deadline = Instant::now() + interval;
+        if !sink.wait(deadline) {
+            return;
+        }
+        collector
+            .poll(deadline.saturating_duration_since(Instant::now()))
+            .unwrap();
+
+        let snapshot = inspect_processes(targets).expect("Can't find the process");
+        let data = collector.drain().unwrap();
+        let curr_stat = procfs::read_stat();
+        let rec = Record::new(snapshot, system_load(&prev_stat, &curr_stat), data);
+
+        // rewritten every interval, so the file is complete whenever mole is stopped
+        if let Some((path, min)) = wakeup_graph {
+            graph.add(&rec);
+            graph
+                .write(path, min)
+                .expect("Can't write the wakeup graph");
+        }
+
+        let start = std::mem::replace(&mut prev, rec.snapshot.clone());
+        sink.add(&start, rec);
+
+        if let Some(status) = child.and_then(|c| c.try_wait()) {
+            sink.finish(status);
+            std::process::exit(status);
+        }
+
+        prev_stat = curr_stat;
+    }
+}
+
+// Tables of every interval on stdout, along with the files asked for
+struct Printer<'a> {
+    args: &'a CliArgs,
+    view: &'a mut View,
+    syms: syms::Symbolizer,
+    folded: Option<BufWriter<File>>,
+    csv_wakeups: Option<BufWriter<File>>,
+    csv_slices: Option<BufWriter<File>>,
+    summary: Option<Summary>, // of a launched command
+}
+
+impl Sink for Printer<'_> {
+    fn add(&mut self, prev: &ProcessDataSnapshot, rec: Record) {
+        let (format, tid) = (self.args.format, self.args.tid);
+
+        self.syms.reset();
+        match tid {
+            Some(tid) => focus::show(format, tid, prev, &rec),
+            None => {
+                let view = &mut *self.view;
+                let syms = if self.args.waker_stacks {
+                    Some(&mut self.syms)
+                } else {
+                    None
+                };
+                show_record(format, view, prev, &rec, syms);
+            }
+        }
+
+        let (time, curr, data) = (&rec.time, &rec.snapshot, &rec.data);
+        if let Some(out) = self.csv_wakeups.as_mut() {
+            write_wakeups_csv(out, time, &data.wakeups, curr).expect("Can't write the CSV file");
+        }
+
+        if let Some(out) = self.csv_slices.as_mut() {
+            write_slices_csv(out, time, &data.slices, curr, &self.view.group_by)
+                .expect("Can't write the CSV file");
+        }
+
+        if let Some(out) = self.folded.as_mut() {
+            write_folded(out, &data.offcpu_stacks, curr, &mut self.syms)
+                .expect("Can't write stacks");
+        }
+
+        if let Some(summary) = self.summary.as_mut() {
+            summary.add(curr, data);
+        }
+    }
+
+    fn finish(&mut self, status: i32) {
+        if let Some(summary) = self.summary.take() {
+            let format = self.args.format;
+            let view = &mut *self.view;
+            let syms = if self.args.waker_stacks {
+                Some(&mut self.syms)
+            } else {
+                None
+            };
+            summary.print(view, format, syms, status);
+        }
+    }
+}
+
+// The full-screen view, see tui
+struct Interactive<'a> {
+    ui: Option<tui::Ui>, // taken to restore the terminal
+    view: &'a mut View,
+    shown: Option<(ProcessDataSnapshot, Record)>,
+}
+
+impl Sink for Interactive<'_> {
+    // keys are applied to the last shown interval right away
+    fn wait(&mut self, deadline: Instant) -> bool {
+        let ui = match self.ui.as_mut() {
+            Some(ui) => ui,
+            None => return false,
+        };
+
+        while let Some(key) = ui.next_key(deadline) {
+            if !ui.handle_key(key, self.view) {
+                return false;
+            }
+            if let Some((prev, rec)) = &self.shown {
+                ui.draw(self.view, prev, rec);
+            }
+        }
+
+        true
+    }
+
+    // a paused view keeps the interval it shows, the data is still drained
+    fn add(&mut self, prev: &ProcessDataSnapshot, rec: Record) {
+        if let Some(ui) = self.ui.as_mut().filter(|ui| !ui.paused) {
+            ui.draw(self.view, prev, &rec);
+            self.shown = Some((prev.clone(), rec));
+        }
+    }
+
+    fn finish(&mut self, _status: i32) {
+        self.ui.take();
+    }
+}
+
+// Prometheus metrics of the run so far, see metrics
+struct Exporter {
+    metrics: metrics::Metrics,
+    page: Arc<Mutex<String>>,
+    by_comm: bool,
+    expire_after: u64,
+}
+
+impl Sink for Exporter {
+    fn add(&mut self, prev: &ProcessDataSnapshot, rec: Record) {
+        update_metrics(
+            &mut self.metrics,
+            prev,
+            &rec.snapshot,
+            &rec.data,
+            self.by_comm,
+        );
+        self.metrics.expire(self.expire_after);
+        *self.page.lock().unwrap() = self.metrics.render();
+    }
+}
+
+// A line of the recording per interval
+struct Recorder {
+    out: BufWriter<File>,
+}
+
+impl Sink for Recorder {
+    fn add(&mut self, _prev: &ProcessDataSnapshot, rec: Record) {
+        write_record(&mut self.out, &rec).expect("Can't write the recording");
+    }
+}
+
+// The run so far against a recorded one
+struct Differ {
+    base: diff::Profile,
+    live: diff::Profile,
+    opts: diff::Options,
+}
+
+impl Sink for Differ {
+    fn add(&mut self, prev: &ProcessDataSnapshot, rec: Record) {
+        self.live.add(prev, &rec);
+        diff::print_diff(&self.base, &self.live, &self.opts);
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    // keep collecting and expose cumulative counters on http://LISTEN/metrics
+    Serve {
+        #[structopt(long)]
+        listen: String,
+
+        // label series with thread names only, summing up threads of the same name
+        #[structopt(long)]
+        by_comm: bool,
+
+        // drop series of threads not seen for this many intervals
+        #[structopt(long, default_value = "60")]
+        expire_after: u64,
+    },
+
+    // save the collected data instead of printing it
+    Record {
+        #[structopt(short = "o", long, parse(from_os_str))]
+        output: PathBuf,
+    },
+
+    // print the tables of a recording
+    Report {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    // compare two recordings, or a recording and the live process
+    Diff {
+        #[structopt(parse(from_os_str))]
+        base: PathBuf,
+
+        #[structopt(parse(from_os_str))]
+        new: Option<PathBuf>,
+
+        // line threads up by name instead of tid
+        #[structopt(long)]
+        by_comm: bool,
+    },
 }
 
 #[derive(Debug, StructOpt)]
 struct CliArgs {
-    #[structopt(short = "p", long, conflicts_with = "cmd")]
-    pid: Option<i32>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
 
-    // #[structopt(short="c", long, conflicts_with="pid")]
-    // cmd: Option<String>,
-    //
-    #[structopt(short = "s", long)]
+    // one or more processes: -p 1,2 or -p 1 -p 2
+    #[structopt(
+        short = "p",
+        long,
+        global = true,
+        number_of_values = 1,
+        use_delimiter = true,
+        conflicts_with = "cmd"
+    )]
+    pid: Vec<i32>,
+
+    // profile every process of a cgroup v2, e.g. /sys/fs/cgroup/system.slice/foo.service
+    #[structopt(
+        long,
+        global = true,
+        parse(from_os_str),
+        conflicts_with_all = &["cmd", "pid"]
+    )]
+    cgroup: Option<PathBuf>,
+
+    // launch the command and profile it until it exits: mole -- cmd args
+    #[structopt(last = true)]
+    cmd: Vec<String>,
+
+    #[structopt(short = "s", long, global = true)]
     sort_by: Option<String>,
 
-    #[structopt(short = "f", long)]
+    #[structopt(short = "f", long, global = true)]
     filter_by: Option<String>,
 
     #[structopt(short = "t", long, required = false, default_value = "1000")]
     sleep_ms: u64,
 
-    #[structopt(short = "n", long)]
+    #[structopt(short = "n", long, global = true)]
     top: Option<usize>,
+
+    // merge threads with the same name (comm), or names matching a regex, into one row
+    #[structopt(short = "g", long, global = true)]
+    group_by: Option<String>,
+
+    // text tables, one JSON document per interval or a CSV row per thread per interval
+    #[structopt(
+        long,
+        global = true,
+        default_value = "text",
+        possible_values = &["text", "json", "csv"]
+    )]
+    format: output::Format,
+
+    // write the wakeup tables of every interval as CSV
+    #[structopt(long, parse(from_os_str))]
+    csv_wakeups: Option<PathBuf>,
+
+    // write the slice tables of every interval as CSV
+    #[structopt(long, parse(from_os_str))]
+    csv_slices: Option<PathBuf>,
+
+    // append off-cpu stacks of voluntary switches in folded format
+    #[structopt(long, parse(from_os_str))]
+    offcpu_stacks: Option<PathBuf>,
+
+    // count wakeups per waker stack and show the top ones for busy edges
+    #[structopt(long)]
+    waker_stacks: bool,
+
+    // add user space frames to off-cpu and waker stacks
+    #[structopt(long)]
+    user_stacks: bool,
+
+    // write all wakeups since the start as a graph: an adjacency list for *.json, DOT otherwise
+    #[structopt(long, global = true, parse(from_os_str))]
+    wakeup_graph: Option<PathBuf>,
+
+    // leave edges with fewer wakeups out of the graph
+    #[structopt(long, global = true, default_value = "1")]
+    wakeup_graph_min: u64,
+
+    // show everything about one thread of the process (or the processes)
+    #[structopt(long, global = true, conflicts_with_all = &["cmd", "cgroup"])]
+    tid: Option<i32>,
+
+    // follow chains of wakeups (A wakes B, B wakes C) and show the time each hop takes
+    #[structopt(long)]
+    chains: bool,
+
+    // count migrations and wakeups landing away from the waker's cpu, llc or numa node
+    #[structopt(long)]
+    placement: bool,
+
+    // split the time of every cpu between the process, others and interrupts
+    #[structopt(long)]
+    cpu_share: bool,
+
+    // list threads of other processes which preempted ours, implied by --cpu-share
+    #[structopt(long)]
+    preemptors: bool,
+
+    // list every thread created or exited with its parent, lifetime and cpu time
+    #[structopt(long)]
+    lifecycle: bool,
+
+    // full-screen view of the latest interval driven by keys instead of scrolling tables
+    #[structopt(short = "i", long)]
+    interactive: bool,
 }
 
 fn main() {
     let mut table = table![
         ("pid", 8),
+        ("tgid", 8),
         ("comm", 16),
         ("usr%", 4),
         ("sys%", 4),
@@ -329,42 +1802,233 @@ fn main() {
     let args = CliArgs::from_args();
     table.top = args.top;
 
-    if let Some(sort_by) = args.sort_by {
-        table.sort_by = Some(
-            table
-                .column_index_by_desc(&sort_by)
-                .expect("Invalid column specified"),
-        );
+    if args.tid.is_some() && args.format == output::Format::Csv {
+        panic!("--tid only supports text and json formats");
+    }
+    if matches!(args.command, Some(Command::Diff { .. })) && args.format == output::Format::Csv {
+        panic!("diff only supports text and json formats");
+    }
+    // recorded stacks are raw addresses, which need the live processes to be symbolized
+    if matches!(args.command, Some(Command::Report { .. }))
+        && (args.offcpu_stacks.is_some() || args.waker_stacks)
+    {
+        panic!("report doesn't support --offcpu-stacks and --waker-stacks");
     }
 
-    if let Some(filter_by) = args.filter_by {
-        table.filter_by = Some(
-            table
-                .column_index_by_desc(&filter_by)
-                .expect("Invalid column specified"),
-        );
+    let group_by = args.group_by.as_deref().map(GroupBy::new);
+    label_groups(&mut table, &group_by);
+
+    // diff tables have columns of their own
+    let diffing = matches!(args.command, Some(Command::Diff { .. }));
+
+    // so has the system-wide table, until a process is picked
+    let system_wide = args.command.is_none()
+        && args.tid.is_none()
+        && args.pid.is_empty()
+        && args.cgroup.is_none()
+        && args.cmd.is_empty();
+
+    // system-wide, the columns are checked against the processes table first
+    // and against this one once a process is picked
+    let check_columns = |table: &mut output::Table| {
+        if let (false, Some(sort_by)) = (diffing, &args.sort_by) {
+            table.sort_by = Some(
+                table
+                    .column_index_by_desc(sort_by)
+                    .expect("Invalid column specified"),
+            );
+        }
+        if let (false, Some(filter_by)) = (diffing, &args.filter_by) {
+            table.filter_by = Some(
+                table
+                    .column_index_by_desc(filter_by)
+                    .expect("Invalid column specified"),
+            );
+        }
+    };
+    if !system_wide {
+        check_columns(&mut table);
     }
 
-    let pid = args.pid.expect("Pid is not specififed");
+    let mut view = View {
+        table,
+        group_by,
+        topology: None,
+    };
 
-    loop {
-        let prev_stat = procfs::read_stat();
-        let prev = inspect_process(pid).expect("Can't find the process");
+    if let Some(Command::Report { file }) = &args.command {
+        let wakeup_graph = args
+            .wakeup_graph
+            .as_deref()
+            .map(|path| (path, args.wakeup_graph_min));
+        report(file, args.format, &mut view, args.tid, wakeup_graph);
+        return;
+    }
 
-        let (wakeups, mut slices) =
-            bpf::read_events(pid, Duration::from_millis(args.sleep_ms), false).unwrap();
+    let diff_opts = diff::Options {
+        sort_by: args.sort_by.clone(),
+        filter_by: args.filter_by.clone(),
+        top: args.top,
+        format: args.format,
+    };
 
-        let curr_stat = procfs::read_stat();
-        let curr = inspect_process(pid).expect("Can't find the process");
+    if let Some(Command::Diff {
+        base,
+        new: Some(new),
+        by_comm,
+    }) = &args.command
+    {
+        let base = diff::Profile::load(read_records(base), *by_comm);
+        let new = diff::Profile::load(read_records(new), *by_comm);
+        diff::print_diff(&base, &new, &diff_opts);
+        return;
+    }
 
-        print_delta_procs(
-            &mut table,
-            &prev,
-            &curr,
-            system_load(&prev_stat, &curr_stat),
-        );
+    let mut child = if args.cmd.is_empty() {
+        None
+    } else {
+        Some(launch::spawn(&args.cmd).expect("Can't launch the command"))
+    };
 
-        print_wakeups(&wakeups, &curr);
-        print_slices(&mut slices, &curr);
+    let targets = match (&child, &args.cgroup) {
+        (Some(child), _) => Targets::Pids(vec![child.pid]),
+        (None, Some(cgroup)) => Targets::Cgroup(cgroup.clone()),
+        (None, None) if !args.pid.is_empty() => Targets::Pids(args.pid.clone()),
+        (None, None) if args.tid.is_some() => {
+            let tid = args.tid.unwrap();
+            let status = procfs::read_proc_status(tid).expect("Can't find the thread");
+            Targets::Pids(vec![status.tgid])
+        }
+        (None, None) if system_wide => {
+            let opts = system::Options {
+                sort_by: args.sort_by.clone(),
+                filter_by: args.filter_by.clone(),
+                top: args.top,
+                sleep_ms: args.sleep_ms,
+            };
+            let pid = system::top(&opts, args.format);
+            check_columns(&mut view.table);
+            Targets::Pids(vec![pid])
+        }
+        (None, None) => panic!("Pid is not specififed"),
+    };
+
+    let summary = child.as_ref().map(|_| Summary::new(&targets));
+
+    let folded = args
+        .offcpu_stacks
+        .as_ref()
+        .map(|path| BufWriter::new(File::create(path).expect("Can't create the stacks file")));
+    let syms = syms::Symbolizer::new();
+
+    // read once, recordings keep it for reports on other machines
+    if args.placement {
+        view.topology = Some(topology::Topology::read());
     }
+
+    let opts = bpf::Options {
+        pids: match &targets {
+            Targets::Pids(pids) => pids.clone(),
+            Targets::Cgroup(_) => vec![],
+        },
+        // the id of a cgroup v2 is the inode number of its directory
+        cgroup: match &targets {
+            Targets::Pids(_) => None,
+            Targets::Cgroup(path) => Some(fs::metadata(path).expect("Can't find the cgroup").ino()),
+        },
+        cgroup_level: match &targets {
+            Targets::Pids(_) => 0,
+            Targets::Cgroup(path) => procfs::cgroup_level(path).expect("Can't find the cgroup"),
+        },
+        offcpu_stacks: folded.is_some(),
+        waker_stacks: args.waker_stacks,
+        user_stacks: args.user_stacks,
+        chains: args.chains,
+        placement: args.placement,
+        cpu_share: args.cpu_share,
+        preemptors: args.preemptors,
+        lifecycle: args.lifecycle,
+    };
+
+    let mut collector = bpf::Collector::new(&opts, false).expect("Can't load BPF programs");
+    if let Some(child) = child.as_mut() {
+        child.resume();
+    }
+
+    let prev_stat = procfs::read_stat();
+    let prev = inspect_processes(&targets).expect("Can't find the process");
+
+    let mut sink: Box<dyn Sink> = match &args.command {
+        Some(Command::Serve {
+            listen,
+            by_comm,
+            expire_after,
+        }) => {
+            let page = Arc::new(Mutex::new(String::new()));
+            metrics::serve(listen, page.clone()).expect("Can't listen on the address");
+
+            Box::new(Exporter {
+                metrics: metrics::Metrics::default(),
+                page,
+                by_comm: *by_comm,
+                expire_after: *expire_after,
+            })
+        }
+        Some(Command::Record { output }) => {
+            let mut out = BufWriter::new(File::create(output).expect("Can't create the recording"));
+            let first = Record::first(prev.clone(), view.topology.take());
+            write_record(&mut out, &first).expect("Can't write the recording");
+
+            Box::new(Recorder { out })
+        }
+        Some(Command::Diff { base, by_comm, .. }) => Box::new(Differ {
+            base: diff::Profile::load(read_records(base), *by_comm),
+            live: diff::Profile::new(*by_comm, &Record::first(prev.clone(), None)),
+            opts: diff_opts,
+        }),
+        _ if args.interactive => Box::new(Interactive {
+            ui: Some(tui::Ui::new(args.tid).expect("Can't set up the terminal")),
+            view: &mut view,
+            shown: None,
+        }),
+        _ => {
+            if args.format == output::Format::Csv {
+                println!("time,{}", view.table.csv_header());
+            }
+
+            let csv_wakeups = args.csv_wakeups.as_ref().map(|path| {
+                let table = top_events_table(&HashMap::new(), &prev, None);
+                create_csv(path, &format!("time,table,{}", table.csv_header()))
+            });
+            let csv_slices = args.csv_slices.as_ref().map(|path| {
+                let table = slices_table(&bpf::Hists::new(), &prev, &view.group_by);
+                create_csv(path, &format!("time,{}", table.csv_header()))
+            });
+
+            Box::new(Printer {
+                args: &args,
+                view: &mut view,
+                syms,
+                folded,
+                csv_wakeups,
+                csv_slices,
+                summary,
+            })
+        }
+    };
+
+    let wakeup_graph = args
+        .wakeup_graph
+        .as_deref()
+        .map(|path| (path, args.wakeup_graph_min));
+    let interval = Duration::from_millis(args.sleep_ms);
+    collect(
+        &mut collector,
+        &targets,
+        (prev_stat, prev),
+        interval,
+        child.as_ref(),
+        wakeup_graph,
+        sink.as_mut(),
+    );
 }
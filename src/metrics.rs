@@ -0,0 +1,210 @@
+use crate::hist::{self, Histogram};
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Escapes a value for the Prometheus text format, e.g. comm="a\"b"
+pub fn label(name: &str, value: &str) -> String {
+    let value = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+
+    format!("{}=\"{}\"", name, value)
+}
+
+//
+// Counters and histograms accumulated since the start, rendered in the
+// Prometheus text exposition format. Series are keyed by their labels,
+// e.g. `pid="42",comm="worker"`.
+//
+#[derive(Default)]
+pub struct Metrics {
+    help: BTreeMap<&'static str, &'static str>,
+    counters: BTreeMap<&'static str, BTreeMap<String, u64>>,
+    hists: BTreeMap<&'static str, BTreeMap<String, Histogram>>,
+    interval: u64,
+    seen: HashMap<String, u64>, // labels -> the last interval they were updated in
+}
+
+impl Metrics {
+    pub fn add_counter(&mut self, name: &'static str, help: &'static str, labels: String, v: u64) {
+        self.help.insert(name, help);
+        self.seen.insert(labels.clone(), self.interval);
+        *self
+            .counters
+            .entry(name)
+            .or_default()
+            .entry(labels)
+            .or_insert(0) += v;
+    }
+
+    pub fn add_hist(
+        &mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: String,
+        h: &Histogram,
+    ) {
+        self.help.insert(name, help);
+        self.seen.insert(labels.clone(), self.interval);
+        self.hists
+            .entry(name)
+            .or_default()
+            .entry(labels)
+            .or_insert_with(Histogram::new)
+            .merge(h);
+    }
+
+    //
+    // Drops series which weren't updated for the given number of intervals,
+    // e.g. of threads which are gone, and starts the next interval. A series
+    // coming back starts from zero, which Prometheus takes as a reset.
+    //
+    pub fn expire(&mut self, intervals: u64) {
+        let oldest = (self.interval + 1).saturating_sub(intervals);
+        self.seen.retain(|_, seen| *seen >= oldest);
+
+        let seen = &self.seen;
+        for series in self.counters.values_mut() {
+            series.retain(|labels, _| seen.contains_key(labels));
+        }
+        for series in self.hists.values_mut() {
+            series.retain(|labels, _| seen.contains_key(labels));
+        }
+        self.counters.retain(|_, series| !series.is_empty());
+        self.hists.retain(|_, series| !series.is_empty());
+
+        self.interval += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        for (name, series) in &self.counters {
+            out.push_str(&format!("# HELP {} {}\n", name, self.help[name]));
+            out.push_str(&format!("# TYPE {} counter\n", name));
+            for (labels, v) in series {
+                out.push_str(&format!("{}{{{}}} {}\n", name, labels, v));
+            }
+        }
+
+        for (name, series) in &self.hists {
+            out.push_str(&format!("# HELP {} {}\n", name, self.help[name]));
+            out.push_str(&format!("# TYPE {} histogram\n", name));
+            for (labels, h) in series {
+                for (le, count) in buckets(h) {
+                    out.push_str(&format!(
+                        "{}_bucket{{{},le=\"{}\"}} {}\n",
+                        name, labels, le, count
+                    ));
+                }
+                out.push_str(&format!("{}_sum{{{}}} {}\n", name, labels, h.sum));
+                out.push_str(&format!("{}_count{{{}}} {}\n", name, labels, h.count()));
+            }
+        }
+
+        out
+    }
+}
+
+// Cumulative buckets up to powers of two minus one, which are the last
+// values of some slots, so every slot falls entirely into a bucket. The
+// last slot is open-ended and only counts towards +Inf.
+fn buckets(h: &Histogram) -> Vec<(String, u64)> {
+    let mut ret = vec![];
+    let mut seen = 0;
+    let mut slot = 0;
+
+    for i in 0..=32 {
+        let le = (1u64 << i) - 1;
+        while slot + 1 < hist::SLOTS && hist::slot_value(slot + 1) - 1 <= le {
+            seen += h.slots[slot];
+            slot += 1;
+        }
+        ret.push((le.to_string(), seen));
+    }
+    ret.push(("+Inf".to_string(), h.count()));
+
+    ret
+}
+
+//
+// Serves the latest rendered metrics on GET /metrics from a background
+// thread, the page is replaced by the caller after every interval.
+//
+pub fn serve(addr: &str, page: Arc<Mutex<String>>) -> Result<()> {
+    let listener = TcpListener::bind(addr)?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+
+            // a stuck client mustn't block the scrapes of everyone else
+            let timeout = Some(Duration::from_secs(5));
+            if stream.set_read_timeout(timeout).is_err()
+                || stream.set_write_timeout(timeout).is_err()
+            {
+                continue;
+            }
+
+            // GET /metrics HTTP/1.1
+            let mut request = String::new();
+            if BufReader::new(&stream).read_line(&mut request).is_err() {
+                continue;
+            }
+
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            let response = if path == "/metrics" {
+                let body = page.lock().unwrap().clone();
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string()
+            };
+
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+
+    Ok(())
+}
+
+#[test]
+fn render_hist() {
+    let mut h = Histogram::new();
+    h.slots[0] = 1; // 0
+    h.slots[5] = 2; // 5
+    h.slots[7] = 1; // 7, on the edge of a bucket
+    h.slots[hist::SLOTS - 1] = 1;
+    h.sum = 17 + (1 << 33);
+
+    let mut m = Metrics::default();
+    m.add_hist("t", "test", label("comm", "a\"b"), &h);
+    let out = m.render();
+
+    assert!(out.contains("t_bucket{comm=\"a\\\"b\",le=\"0\"} 1\n"));
+    assert!(out.contains("t_bucket{comm=\"a\\\"b\",le=\"3\"} 1\n"));
+    assert!(out.contains("t_bucket{comm=\"a\\\"b\",le=\"7\"} 4\n"));
+    assert!(out.contains("t_bucket{comm=\"a\\\"b\",le=\"4294967295\"} 4\n"));
+    assert!(out.contains("t_bucket{comm=\"a\\\"b\",le=\"+Inf\"} 5\n"));
+    assert!(out.contains("t_count{comm=\"a\\\"b\"} 5\n"));
+
+    m.expire(2);
+    m.add_counter("c", "test", label("pid", "1"), 1);
+    m.expire(2);
+    assert!(m.render().contains("t_count"));
+    m.expire(2);
+    assert!(!m.render().contains("t_count"));
+    assert!(m.render().contains("c{pid=\"1\"} 1"));
+}
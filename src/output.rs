@@ -1,6 +1,27 @@
+use anyhow::{bail, Result};
 use std::cmp::Ordering;
 use std::str::FromStr;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => bail!("Unknown format: {}", s),
+        }
+    }
+}
+
 pub enum Data {
     UInt(u64),
     Int(i64),
@@ -19,6 +40,7 @@ pub struct Table {
     pub sort_by: Option<usize>,
     pub filter_by: Option<usize>,
     pub top: Option<usize>,
+    pub footer: Option<Vec<Data>>, // e.g. totals, shown below the rows as is
 }
 
 fn default_fmt(data: &Data, column: &Column) -> String {
@@ -30,6 +52,14 @@ fn default_fmt(data: &Data, column: &Column) -> String {
     }
 }
 
+fn csv_field(s: &str) -> String {
+    if s.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
 fn compare_data(a: &Data, b: &Data) -> Ordering {
     match (a, b) {
         (Data::UInt(x), Data::UInt(y)) => x.cmp(&y),
@@ -45,10 +75,28 @@ impl Data {
         match self {
             Data::UInt(v) => *v == 0,
             Data::Int(v) => *v == 0,
-            Data::Float(v) => *v < 0.0001,
+            Data::Float(v) => v.abs() < 0.0001, // differences can be negative
             Data::Text(v) => v.is_empty(),
         }
     }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Data::UInt(v) => serde_json::json!(v),
+            Data::Int(v) => serde_json::json!(v),
+            Data::Float(v) => serde_json::json!(v),
+            Data::Text(v) => serde_json::json!(v),
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        match self {
+            Data::UInt(v) => v.to_string(),
+            Data::Int(v) => v.to_string(),
+            Data::Float(v) => format!("{:.1}", v),
+            Data::Text(v) => csv_field(v),
+        }
+    }
 }
 
 impl Table {
@@ -65,17 +113,29 @@ impl Table {
         self.data.push(data);
     }
 
+    fn sort(&mut self) {
+        if let Some(sort_by) = self.sort_by {
+            self.data
+                .sort_unstable_by(|a, b| compare_data(&a[sort_by], &b[sort_by]));
+        }
+    }
+
+    // Only the last top rows are shown if the number is limited
+    pub fn rows(&self) -> &[Vec<Data>] {
+        let skip = match self.top {
+            Some(top) => self.data.len().saturating_sub(top),
+            None => 0,
+        };
+
+        &self.data[skip..]
+    }
+
     pub fn display_table(&mut self) -> String {
         let mut output = String::new();
         let delimiter = String::from(" ");
         let newline = String::from("\n");
 
-        // sort data
-        if self.sort_by.is_some() {
-            let sort_by = self.sort_by.unwrap();
-            self.data
-                .sort_unstable_by(|a, b| compare_data(&a[sort_by], &b[sort_by]));
-        }
+        self.sort();
 
         // print titles
         for column in &self.columns {
@@ -83,27 +143,77 @@ impl Table {
             output.push_str(&delimiter);
         }
         output.push_str(&newline);
-        output.push_str(&"-".repeat(output.len() - 1));
+        let separator = "-".repeat(output.len() - 1);
+        output.push_str(&separator);
         output.push_str(&newline);
 
         // print data
-        let mut y = 0;
-        for row in &self.data {
-            // ? print last top elements
-            if let Some(top) = self.top {
-                if y + top < self.data.len() {
-                    y += 1;
-                    continue;
-                }
-            }
-            let mut x = 0;
-            for cell in row {
-                output.push_str(&default_fmt(cell, &self.columns[x]));
-                output.push_str(&delimiter);
-                x += 1;
-            }
+        for row in self.rows() {
+            output.push_str(&self.format_row(row));
+        }
+
+        if let Some(footer) = &self.footer {
+            output.push_str(&separator);
             output.push_str(&newline);
-            y += 1;
+            output.push_str(&self.format_row(footer));
+        }
+
+        output
+    }
+
+    fn format_row(&self, row: &[Data]) -> String {
+        let mut output = String::new();
+
+        for (cell, column) in row.iter().zip(&self.columns) {
+            output.push_str(&default_fmt(cell, column));
+            output.push(' ');
+        }
+        output.push('\n');
+
+        output
+    }
+
+    fn json_row(&self, row: &[Data]) -> serde_json::Value {
+        self.columns
+            .iter()
+            .zip(row)
+            .map(|(column, cell)| (column.title.clone(), cell.to_json()))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    // Rows in the display order as objects keyed by column titles
+    pub fn json_rows(&mut self) -> serde_json::Value {
+        self.sort();
+
+        self.rows().iter().map(|row| self.json_row(row)).collect()
+    }
+
+    pub fn json_footer(&self) -> serde_json::Value {
+        match &self.footer {
+            Some(footer) => self.json_row(footer),
+            None => serde_json::Value::Null,
+        }
+    }
+
+    pub fn csv_header(&self) -> String {
+        let titles: Vec<_> = self.columns.iter().map(|c| csv_field(&c.title)).collect();
+
+        titles.join(",")
+    }
+
+    // A line per row in the display order and the footer, each starting with
+    // the leading fields
+    pub fn csv_rows(&mut self, leading: &[&str]) -> String {
+        let mut output = String::new();
+
+        self.sort();
+        for row in self.rows().iter().chain(&self.footer) {
+            let mut fields: Vec<_> = leading.iter().map(|f| csv_field(f)).collect();
+            fields.extend(row.iter().map(|cell| cell.to_csv()));
+
+            output.push_str(&fields.join(","));
+            output.push('\n');
         }
 
         output
@@ -111,6 +221,7 @@ impl Table {
 
     pub fn clear_data(&mut self) {
         self.data.clear();
+        self.footer = None;
     }
 
     pub fn column_index_by_desc(&self, s: &str) -> Option<usize> {
@@ -135,6 +246,7 @@ macro_rules! table {
 	    sort_by: Some(0),
 	    filter_by: None,
 	    top: None,
+	    footer: None,
 	}
     }
 }
@@ -170,3 +282,52 @@ fn create_test_table() {
 
     println!("{}", t.display_table());
 }
+
+#[test]
+fn json_table_rows() {
+    let mut t = table![("pid", 8), ("comm", 16), ("usr%", 4)];
+    t.sort_by = Some(2);
+    t.top = Some(1);
+
+    t.add_row(vec![
+        Data::Int(1),
+        Data::Text("aaa".to_string()),
+        Data::Float(0.5),
+    ]);
+    t.add_row(vec![
+        Data::Int(2),
+        Data::Text("bbb".to_string()),
+        Data::Float(1.5),
+    ]);
+
+    assert_eq!(
+        t.json_rows(),
+        serde_json::json!([{"pid": 2, "comm": "bbb", "usr%": 1.5}])
+    );
+}
+
+#[test]
+fn csv_table_rows() {
+    let mut t = table![("pid", 8), ("comm", 16), ("usr%", 4)];
+
+    t.add_row(vec![
+        Data::Int(2),
+        Data::Text("b,b".to_string()),
+        Data::Float(1.5),
+    ]);
+    t.add_row(vec![
+        Data::Int(1),
+        Data::Text("aaa".to_string()),
+        Data::Float(0.5),
+    ]);
+
+    assert_eq!(t.csv_header(), "pid,comm,usr%");
+    assert_eq!(t.csv_rows(&["t0"]), "t0,1,aaa,0.5\nt0,2,\"b,b\",1.5\n");
+
+    t.footer = Some(vec![
+        Data::Text(String::new()),
+        Data::Text("total".to_string()),
+        Data::Float(2.0),
+    ]);
+    assert!(t.csv_rows(&["t0"]).ends_with("t0,,total,2.0\n"));
+}
@@ -1,7 +1,10 @@
+use std::collections::HashMap;
 use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
 use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct StatData {
     pub user: u64,
     pub nice: u64,
@@ -42,15 +45,60 @@ pub fn read_stat() -> StatData {
     panic!("Can't read /proc/stat");
 }
 
+// Same as read_stat(), but for every cpu
+pub fn read_cpu_stats() -> HashMap<u32, StatData> {
+    let mut ret = HashMap::new();
+    let raw = fs::read_to_string("/proc/stat").unwrap_or_default();
+
+    for line in raw.lines() {
+        // cpu0 4866834 20029 1046183 52712378 30689 248823 132587 0 35067 0
+        let mut items = line.split_whitespace();
+        let cpu = items
+            .next()
+            .and_then(|c| c.strip_prefix("cpu"))
+            .and_then(|c| u32::from_str(c).ok());
+        let cpu = match cpu {
+            Some(cpu) => cpu,
+            None => continue, // the summary line and everything else
+        };
+
+        let mut next = || {
+            items
+                .next()
+                .and_then(|i| u64::from_str(i).ok())
+                .unwrap_or(0)
+        };
+        ret.insert(
+            cpu,
+            StatData {
+                user: next(),
+                nice: next(),
+                system: next(),
+                idle: next(),
+                iowait: next(),
+                irq: next(),
+                softirq: next(),
+                steal: next(),
+                guest: next(),
+                guest_nice: next(),
+            },
+        );
+    }
+
+    ret
+}
+
 #[derive(Debug)]
 pub struct ProcStatData {
     pub utime: u64,
     pub stime: u64,
+    pub processor: u32, // cpu the thread last ran on
 }
 
 fn parse_proc_stat(f: &str) -> Option<ProcStatData> {
     let raw = fs::read_to_string(f).ok()?;
-    let mut items = raw.split_whitespace();
+    // comm can have spaces in it, so start from the state after it
+    let mut items = raw[raw.rfind(')')? + 1..].split_whitespace();
 
     // (0) pid  %d
     // (1) comm  %s
@@ -106,8 +154,9 @@ fn parse_proc_stat(f: &str) -> Option<ProcStatData> {
     // (51) exit_code  %d  (since Linux 3.5)  [PT]
 
     let data = ProcStatData {
-        utime: u64::from_str(items.nth(13).unwrap()).unwrap(),
+        utime: u64::from_str(items.nth(11).unwrap()).unwrap(),
         stime: u64::from_str(items.nth(0).unwrap()).unwrap(),
+        processor: u32::from_str(items.nth(23).unwrap()).unwrap(),
     };
 
     Some(data)
@@ -127,6 +176,7 @@ pub fn read_thread_stat(tgid: i32, pid: i32) -> Option<ProcStatData> {
 #[derive(Debug)]
 pub struct ProcStatusData {
     pub name: String,
+    pub tgid: i32,
     pub vctxsw: u64,
     pub ivctxsw: u64,
 }
@@ -134,6 +184,7 @@ pub struct ProcStatusData {
 pub fn read_proc_status(pid: i32) -> Option<ProcStatusData> {
     let mut data = ProcStatusData {
         name: String::new(),
+        tgid: 0,
         vctxsw: 0,
         ivctxsw: 0,
     };
@@ -142,6 +193,8 @@ pub fn read_proc_status(pid: i32) -> Option<ProcStatusData> {
     for line in raw.lines() {
         if line.starts_with("Name:") {
             data.name = line.split_whitespace().nth(1).unwrap().to_string();
+        } else if line.starts_with("Tgid:") {
+            data.tgid = i32::from_str(line.split_whitespace().nth(1).unwrap()).unwrap();
         } else if line.starts_with("voluntary_ctxt_switches:") {
             data.vctxsw = u64::from_str(line.split_whitespace().nth(1).unwrap()).unwrap();
         } else if line.starts_with("nonvoluntary_ctxt_switches:") {
@@ -197,3 +250,43 @@ pub fn read_proc_threads(pid: i32) -> Option<ProcTask> {
         dir: fs::read_dir(format!("/proc/{}/task/", pid)).ok()?,
     })
 }
+
+// Processes (tgids) currently attached to a cgroup v2 directory or its children
+pub fn read_cgroup_procs(cgroup: &Path) -> Option<Vec<i32>> {
+    let raw = fs::read_to_string(cgroup.join("cgroup.procs")).ok()?;
+    let mut ret: Vec<_> = raw.lines().filter_map(|l| i32::from_str(l).ok()).collect();
+
+    // children may go away while they're read
+    for entry in fs::read_dir(cgroup).ok()?.flatten() {
+        if matches!(entry.file_type(), Ok(t) if t.is_dir()) {
+            ret.extend(read_cgroup_procs(&entry.path()).unwrap_or_default());
+        }
+    }
+
+    Some(ret)
+}
+
+// Depth of a cgroup v2 directory under the root of its hierarchy, which is
+// the last directory up the path on the same filesystem
+pub fn cgroup_level(cgroup: &Path) -> Option<u32> {
+    let cgroup = fs::canonicalize(cgroup).ok()?;
+    let dev = fs::metadata(&cgroup).ok()?.dev();
+
+    for (level, dir) in cgroup.ancestors().skip(1).enumerate() {
+        if fs::metadata(dir).ok()?.dev() != dev {
+            return Some(level as u32);
+        }
+    }
+
+    None
+}
+
+// Every process (tgid) in the system
+pub fn read_procs() -> Vec<i32> {
+    match fs::read_dir("/proc") {
+        Ok(dir) => dir
+            .filter_map(|e| i32::from_str(e.ok()?.file_name().to_str()?).ok())
+            .collect(),
+        Err(_) => vec![],
+    }
+}
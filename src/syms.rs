@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+
+// Function symbols sorted by address
+struct SymTab {
+    syms: Vec<(u64, u64, String)>, // address, size (0 if unknown), name
+}
+
+impl SymTab {
+    fn new(mut syms: Vec<(u64, u64, String)>) -> SymTab {
+        syms.sort_unstable_by_key(|s| s.0);
+        SymTab { syms }
+    }
+
+    fn find(&self, addr: u64) -> Option<&str> {
+        let i = match self.syms.binary_search_by_key(&addr, |s| s.0) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+
+        let (start, size, name) = &self.syms[i];
+        if *size == 0 || addr < start + size {
+            Some(name)
+        } else {
+            None
+        }
+    }
+}
+
+fn read_kallsyms() -> SymTab {
+    let mut syms = vec![];
+
+    // ffffffff81000000 T _stext
+    if let Ok(raw) = fs::read_to_string("/proc/kallsyms") {
+        for line in raw.lines() {
+            let mut items = line.split_whitespace();
+            let addr = items.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+            let kind = items.next();
+            let name = items.next();
+
+            if let (Some(addr), Some("t") | Some("T"), Some(name)) = (addr, kind, name) {
+                syms.push((addr, 0, name.to_string()));
+            }
+        }
+    }
+
+    SymTab::new(syms)
+}
+
+struct Elf {
+    syms: SymTab,
+    loads: Vec<(u64, u64, u64)>, // PT_LOAD segments: offset, vaddr, filesz
+}
+
+//
+// Just enough of ELF64 (little endian) to map file offsets to function
+// names: PT_LOAD program headers and STT_FUNC entries of .symtab/.dynsym.
+//
+fn read_elf(path: &str) -> Option<Elf> {
+    let data = fs::read(path).ok()?;
+
+    if data.get(..6)? != b"\x7fELF\x02\x01" {
+        return None;
+    }
+
+    let u16_at = |off: u64| -> Option<u64> {
+        let off = off as usize;
+        Some(u16::from_le_bytes(data.get(off..off + 2)?.try_into().ok()?) as u64)
+    };
+    let u32_at = |off: u64| -> Option<u64> {
+        let off = off as usize;
+        Some(u32::from_le_bytes(data.get(off..off + 4)?.try_into().ok()?) as u64)
+    };
+    let u64_at = |off: u64| -> Option<u64> {
+        let off = off as usize;
+        Some(u64::from_le_bytes(data.get(off..off + 8)?.try_into().ok()?))
+    };
+    let str_at = |off: u64| -> Option<String> {
+        let s = data.get(off as usize..)?;
+        let len = s.iter().position(|c| *c == 0)?;
+        Some(String::from_utf8_lossy(&s[..len]).into_owned())
+    };
+
+    let phoff = u64_at(0x20)?;
+    let shoff = u64_at(0x28)?;
+    let phentsize = u16_at(0x36)?;
+    let phnum = u16_at(0x38)?;
+    let shentsize = u16_at(0x3a)?;
+    let shnum = u16_at(0x3c)?;
+
+    let mut loads = vec![];
+    for i in 0..phnum {
+        let ph = phoff + i * phentsize;
+        if u32_at(ph)? == 1 {
+            // PT_LOAD
+            loads.push((u64_at(ph + 8)?, u64_at(ph + 16)?, u64_at(ph + 32)?));
+        }
+    }
+
+    let mut syms = vec![];
+    for i in 0..shnum {
+        let sh = shoff + i * shentsize;
+        let sh_type = u32_at(sh + 4)?;
+        if sh_type != 2 && sh_type != 11 {
+            // neither SHT_SYMTAB nor SHT_DYNSYM
+            continue;
+        }
+
+        let offset = u64_at(sh + 0x18)?;
+        let size = u64_at(sh + 0x20)?;
+        let entsize = u64_at(sh + 0x38)?;
+        let strtab = u64_at(shoff + u32_at(sh + 0x28)? * shentsize + 0x18)?;
+
+        if entsize == 0 {
+            continue;
+        }
+
+        for j in 0..size / entsize {
+            let sym = offset + j * entsize;
+            let info = data.get((sym + 4) as usize)?;
+            let value = u64_at(sym + 8)?;
+
+            if info & 0xf == 2 && value != 0 {
+                // STT_FUNC
+                let name = str_at(strtab + u32_at(sym)?)?;
+                syms.push((value, u64_at(sym + 16)?, name));
+            }
+        }
+    }
+
+    Some(Elf {
+        syms: SymTab::new(syms),
+        loads,
+    })
+}
+
+impl Elf {
+    fn find(&self, file_offset: u64) -> Option<&str> {
+        let (offset, vaddr, _) = self
+            .loads
+            .iter()
+            .find(|(offset, _, filesz)| *offset <= file_offset && file_offset < offset + filesz)?;
+
+        self.syms.find(file_offset - offset + vaddr)
+    }
+}
+
+struct Mapping {
+    start: u64,
+    end: u64,
+    offset: u64,
+    path: String,
+}
+
+fn read_maps(pid: i32) -> Vec<Mapping> {
+    let mut ret = vec![];
+
+    // 7f0d5c1a1000-7f0d5c1c3000 r-xp 00022000 fd:01 1835533  /usr/lib/libc.so.6
+    if let Ok(raw) = fs::read_to_string(format!("/proc/{}/maps", pid)) {
+        for line in raw.lines() {
+            let items: Vec<_> = line.split_whitespace().collect();
+            if items.len() < 6 || !items[5].starts_with('/') {
+                continue;
+            }
+
+            let mut range = items[0].split('-');
+            let start = range.next().and_then(|s| u64::from_str_radix(s, 16).ok());
+            let end = range.next().and_then(|s| u64::from_str_radix(s, 16).ok());
+            let offset = u64::from_str_radix(items[2], 16).ok();
+
+            if let (Some(start), Some(end), Some(offset)) = (start, end, offset) {
+                ret.push(Mapping {
+                    start,
+                    end,
+                    offset,
+                    path: items[5].to_string(),
+                });
+            }
+        }
+    }
+
+    ret
+}
+
+pub struct Symbolizer {
+    kernel: Option<SymTab>, // loaded on first use
+    elves: HashMap<String, Option<Elf>>,
+    maps: HashMap<i32, Vec<Mapping>>,
+}
+
+impl Symbolizer {
+    pub fn new() -> Symbolizer {
+        Symbolizer {
+            kernel: None,
+            elves: HashMap::new(),
+            maps: HashMap::new(),
+        }
+    }
+
+    // Forget memory mappings, processes might have (un)loaded libraries
+    pub fn reset(&mut self) {
+        self.maps.clear();
+    }
+
+    pub fn kernel(&mut self, addr: u64) -> String {
+        let kernel = self.kernel.get_or_insert_with(read_kallsyms);
+
+        kernel.find(addr).unwrap_or("[unknown]").to_string()
+    }
+
+    pub fn user(&mut self, tgid: i32, addr: u64) -> String {
+        let maps = self.maps.entry(tgid).or_insert_with(|| read_maps(tgid));
+        let map = match maps.iter().find(|m| m.start <= addr && addr < m.end) {
+            Some(map) => map,
+            None => return "[unknown]".to_string(),
+        };
+
+        let file_offset = addr - map.start + map.offset;
+        let elf = self
+            .elves
+            .entry(map.path.clone())
+            .or_insert_with(|| read_elf(&format!("/proc/{}/root{}", tgid, map.path)));
+
+        match elf.as_ref().and_then(|e| e.find(file_offset)) {
+            Some(name) => name.to_string(),
+            None => {
+                let file = map.path.rsplit('/').next().unwrap_or(&map.path);
+                format!("{}+{:#x}", file, file_offset)
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{inspect_thread, output, procfs, system_load, table, ThreadDataSnapshot};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+// Counters of all threads of a process summed up
+#[derive(Default)]
+struct ProcessTotals {
+    comm: String,
+    threads: usize,
+    sum: ThreadDataSnapshot,
+}
+
+fn inspect_system() -> HashMap<i32, ProcessTotals> {
+    let mut ret = HashMap::new();
+
+    for tgid in procfs::read_procs() {
+        let tids = match procfs::read_proc_threads(tgid) {
+            Some(tids) => tids,
+            None => continue,
+        };
+
+        let mut p = ProcessTotals::default();
+        for tid in tids {
+            if let Some(td) = inspect_thread(tgid, tid) {
+                if tid == tgid {
+                    p.comm = td.comm.clone();
+                }
+                p.threads += 1;
+                p.sum.merge(&td);
+            }
+        }
+
+        if p.threads > 0 {
+            ret.insert(tgid, p);
+        }
+    }
+
+    ret
+}
+
+// Threads which exited take their counters with them, so sums can go down
+fn delta(c: &ThreadDataSnapshot, p: &ThreadDataSnapshot) -> ThreadDataSnapshot {
+    ThreadDataSnapshot {
+        utime: c.utime.saturating_sub(p.utime),
+        stime: c.stime.saturating_sub(p.stime),
+        vctxsw: c.vctxsw.saturating_sub(p.vctxsw),
+        ivctxsw: c.ivctxsw.saturating_sub(p.ivctxsw),
+        on_cpu: c.on_cpu.saturating_sub(p.on_cpu),
+        waiting_for_cpu: c.waiting_for_cpu.saturating_sub(p.waiting_for_cpu),
+        slices: c.slices.saturating_sub(p.slices),
+        ..Default::default()
+    }
+}
+
+pub struct Options {
+    pub sort_by: Option<String>,
+    pub filter_by: Option<String>,
+    pub top: Option<usize>,
+    pub sleep_ms: u64,
+}
+
+fn processes_table(opts: &Options) -> output::Table {
+    let mut table = table![
+        ("pid", 8),
+        ("comm", 16),
+        ("threads", 8),
+        ("usr%", 4),
+        ("sys%", 4),
+        ("on_cpu", 10),
+        ("wait", 10),
+        ("slices", 10),
+        ("avg_slice", 10),
+        ("vctxsw", 10),
+        ("ivctxsw", 10)
+    ];
+
+    table.sort_by = Some(5); // sort by on_cpu
+    table.top = Some(opts.top.unwrap_or(20));
+    if let Some(sort_by) = &opts.sort_by {
+        table.sort_by = Some(
+            table
+                .column_index_by_desc(sort_by)
+                .expect("Invalid column specified"),
+        );
+    }
+    if let Some(filter_by) = &opts.filter_by {
+        table.filter_by = Some(
+            table
+                .column_index_by_desc(filter_by)
+                .expect("Invalid column specified"),
+        );
+    }
+
+    table
+}
+
+fn add_rows(
+    table: &mut output::Table,
+    prev: &HashMap<i32, ProcessTotals>,
+    curr: &HashMap<i32, ProcessTotals>,
+    load: u64,
+) {
+    // processes started during the interval are accounted from zero
+    let zero = ProcessTotals::default();
+
+    for (tgid, c) in curr {
+        let p = prev.get(tgid).unwrap_or(&zero);
+        let d = delta(&c.sum, &p.sum);
+        let avg_slice = d.on_cpu.checked_div(d.slices).unwrap_or(0);
+
+        table.add_row(vec![
+            output::Data::Int(*tgid as i64),
+            output::Data::Text(c.comm.clone()),
+            output::Data::UInt(c.threads as u64),
+            output::Data::Float(d.utime as f64 / load as f64 * 100.0),
+            output::Data::Float(d.stime as f64 / load as f64 * 100.0),
+            output::Data::UInt(d.on_cpu),
+            output::Data::UInt(d.waiting_for_cpu),
+            output::Data::UInt(d.slices),
+            output::Data::UInt(avg_slice),
+            output::Data::UInt(d.vctxsw),
+            output::Data::UInt(d.ivctxsw),
+        ]);
+    }
+}
+
+//
+// Pids typed on stdin, one per line. Each pid has to be answered whether to
+// read on, stdin is left to others (e.g. the interactive mode) once it's not.
+//
+fn read_pids() -> (mpsc::Receiver<i32>, mpsc::Sender<bool>) {
+    let (tx, rx) = mpsc::channel();
+    let (more_tx, more_rx) = mpsc::channel();
+
+    std::thread::spawn(move || loop {
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+
+        match line.trim().parse() {
+            Ok(pid) => {
+                if tx.send(pid).is_err() || more_rx.recv() != Ok(true) {
+                    break;
+                }
+            }
+            Err(_) => eprintln!("Not a pid: {}", line.trim()),
+        }
+    });
+
+    (rx, more_tx)
+}
+
+//
+// Processes of the whole system by scheduling pressure, refreshed until a
+// pid of one of them is typed in. Returns the pid to look into.
+//
+pub fn top(opts: &Options, format: output::Format) -> i32 {
+    let interval = Duration::from_millis(opts.sleep_ms);
+    let mut table = processes_table(opts);
+    let (pids, more) = read_pids();
+
+    // CSV of the processes goes to stderr, stdout only gets that of the
+    // threads of the picked one, with a header of its own
+    match format {
+        output::Format::Text => eprintln!("Type a pid and press Enter to see its threads"),
+        output::Format::Csv => eprintln!("time,{}", table.csv_header()),
+        output::Format::Json => (),
+    }
+
+    let mut prev_stat = procfs::read_stat();
+    let mut prev = inspect_system();
+
+    loop {
+        let deadline = Instant::now() + interval;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match pids.recv_timeout(timeout) {
+                Ok(pid) if prev.contains_key(&pid) => return pid,
+                Ok(pid) => {
+                    eprintln!("No such process: {}", pid);
+                    let _ = more.send(true);
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                // stdin is closed, just keep refreshing
+                Err(RecvTimeoutError::Disconnected) => {
+                    std::thread::sleep(timeout);
+                    break;
+                }
+            }
+        }
+
+        let curr_stat = procfs::read_stat();
+        let curr = inspect_system();
+        let load = system_load(&prev_stat, &curr_stat);
+
+        add_rows(&mut table, &prev, &curr, load);
+        match format {
+            output::Format::Text => {
+                println!("{} processes", curr.len());
+                println!("{}", table.display_table());
+            }
+            output::Format::Json => {
+                let doc = serde_json::json!({
+                    "time": chrono::Local::now().to_rfc3339(),
+                    "nr_processes": curr.len(),
+                    "processes": table.json_rows(),
+                });
+                println!("{}", doc);
+            }
+            output::Format::Csv => {
+                let time = chrono::Local::now().to_rfc3339();
+                eprint!("{}", table.csv_rows(&[&time]));
+            }
+        }
+        table.clear_data();
+
+        prev_stat = curr_stat;
+        prev = curr;
+    }
+}
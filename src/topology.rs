@@ -0,0 +1,164 @@
+use crate::bpf;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+
+// How far apart two cpus are, in the order of Placement counters
+pub const DISTANCES: [&str; 4] = ["same_cpu", "same_llc", "same_node", "remote"];
+
+// Cpus of a list like "0-3,8,10-11"
+fn parse_cpu_list(list: &str) -> Vec<u32> {
+    let mut ret = vec![];
+
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut ends = range.splitn(2, '-').filter_map(|n| u32::from_str(n).ok());
+        match (ends.next(), ends.next()) {
+            (Some(first), Some(last)) => ret.extend(first..=last),
+            (Some(cpu), None) => ret.push(cpu),
+            _ => (),
+        }
+    }
+
+    ret
+}
+
+//
+// Last level caches and NUMA nodes of the cpus, as found in sysfs. Cpus
+// which aren't there are considered to share both with any other.
+//
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Topology {
+    llc: HashMap<u32, u32>,  // cpu -> the lowest cpu sharing its last level cache
+    node: HashMap<u32, u32>, // cpu -> numa node
+}
+
+impl Topology {
+    pub fn read() -> Topology {
+        let mut topo = Topology::default();
+        let dir = "/sys/devices/system/cpu";
+
+        let cpus = fs::read_to_string(format!("{}/online", dir)).unwrap_or_default();
+        for cpu in parse_cpu_list(&cpus) {
+            let cpu_dir = format!("{}/cpu{}", dir, cpu);
+
+            // the cache index with the highest level is the last level one
+            let mut llc: Option<(u32, Vec<u32>)> = None;
+            for entry in fs::read_dir(format!("{}/cache", cpu_dir))
+                .into_iter()
+                .flatten()
+            {
+                let path = match entry {
+                    Ok(entry) => entry.path(),
+                    Err(_) => continue,
+                };
+                let level = fs::read_to_string(path.join("level"))
+                    .ok()
+                    .and_then(|l| u32::from_str(l.trim()).ok());
+                let shared = fs::read_to_string(path.join("shared_cpu_list")).ok();
+
+                if let (Some(level), Some(shared)) = (level, shared) {
+                    if !matches!(&llc, Some((l, _)) if *l >= level) {
+                        llc = Some((level, parse_cpu_list(&shared)));
+                    }
+                }
+            }
+            if let Some(first) = llc.and_then(|(_, shared)| shared.into_iter().min()) {
+                topo.llc.insert(cpu, first);
+            }
+
+            for entry in fs::read_dir(&cpu_dir).into_iter().flatten().flatten() {
+                let name = entry.file_name().into_string().unwrap_or_default();
+                if let Some(node) = name.strip_prefix("node") {
+                    if let Ok(node) = u32::from_str(node) {
+                        topo.node.insert(cpu, node);
+                    }
+                }
+            }
+        }
+
+        topo
+    }
+
+    // Index into DISTANCES
+    pub fn distance(&self, a: u32, b: u32) -> usize {
+        if a == b {
+            0
+        } else if shared(&self.llc, a, b) {
+            1
+        } else if shared(&self.node, a, b) {
+            2
+        } else {
+            3
+        }
+    }
+}
+
+fn shared(map: &HashMap<u32, u32>, a: u32, b: u32) -> bool {
+    match (map.get(&a), map.get(&b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    }
+}
+
+// Where a thread ran and was woken up during the interval
+#[derive(Debug, Clone, Default)]
+pub struct Placement {
+    pub migrations: [u64; 4],    // by distance
+    pub wakeups: [u64; 4],       // by distance from the waker's cpu
+    pub cpus: HashMap<u32, u64>, // cpu -> on-cpu us
+}
+
+impl Placement {
+    pub fn merge(&mut self, other: &Placement) {
+        for i in 0..DISTANCES.len() {
+            self.migrations[i] += other.migrations[i];
+            self.wakeups[i] += other.wakeups[i];
+        }
+        for (cpu, us) in &other.cpus {
+            *self.cpus.entry(*cpu).or_insert(0) += us;
+        }
+    }
+
+    // Cpus by the time spent on them, the busiest first
+    pub fn top_cpus(&self) -> Vec<(u32, u64)> {
+        let mut cpus: Vec<_> = self.cpus.iter().map(|(cpu, us)| (*cpu, *us)).collect();
+        cpus.sort_by_key(|(cpu, us)| (std::cmp::Reverse(*us), *cpu));
+
+        cpus
+    }
+}
+
+pub fn placements(data: &bpf::Interval, topo: &Topology) -> HashMap<i32, Placement> {
+    let mut ret: HashMap<i32, Placement> = HashMap::new();
+
+    for ((pid, from, to), count) in &data.migrations {
+        ret.entry(*pid).or_default().migrations[topo.distance(*from, *to)] += count;
+    }
+    for ((pid, from, to), count) in &data.wakeup_cpus {
+        ret.entry(*pid).or_default().wakeups[topo.distance(*from, *to)] += count;
+    }
+    for ((pid, cpu), us) in &data.cpus {
+        ret.entry(*pid).or_default().cpus.insert(*cpu, *us);
+    }
+
+    ret
+}
+
+#[test]
+fn distances() {
+    assert_eq!(parse_cpu_list("0-2,8\n"), vec![0, 1, 2, 8]);
+
+    let mut topo = Topology::default();
+    for cpu in 0..8 {
+        topo.llc.insert(cpu, cpu / 2 * 2);
+        topo.node.insert(cpu, cpu / 4);
+    }
+
+    assert_eq!(topo.distance(1, 1), 0);
+    assert_eq!(topo.distance(0, 1), 1);
+    assert_eq!(topo.distance(1, 2), 2);
+    assert_eq!(topo.distance(3, 4), 3);
+    // unknown cpus share everything
+    assert_eq!(topo.distance(0, 9), 1);
+}
@@ -0,0 +1,285 @@
+use crate::{
+    delta_procs, focus, format_2tables, slices_table, wakeup_tables, ProcessDataSnapshot, Record,
+    View,
+};
+use anyhow::{bail, Result};
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Instant;
+
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    Enter,
+    Esc,
+}
+
+// Keys are passed as is (no echo, no line buffering) while this lives
+struct Terminal {
+    orig: libc::termios,
+}
+
+impl Terminal {
+    fn new() -> Result<Terminal> {
+        let mut orig: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(0, &mut orig) } != 0 {
+            bail!("stdin is not a terminal");
+        }
+
+        // Ctrl-C is read as a key, so the terminal is always restored
+        let mut raw = orig;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(0, libc::TCSANOW, &raw) } != 0 {
+            bail!("Failed to switch the terminal to raw mode");
+        }
+
+        // alternate screen, hidden cursor
+        print!("\x1b[?1049h\x1b[?25l");
+        std::io::stdout().flush()?;
+
+        Ok(Terminal { orig })
+    }
+
+    // Rows and columns, 24x80 if unknown
+    fn size() -> (usize, usize) {
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+
+        if unsafe { libc::ioctl(1, libc::TIOCGWINSZ, &mut ws) } != 0 || ws.ws_row == 0 {
+            return (24, 80);
+        }
+
+        (ws.ws_row as usize, ws.ws_col as usize)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = std::io::stdout().flush();
+        unsafe {
+            libc::tcsetattr(0, libc::TCSANOW, &self.orig);
+        }
+    }
+}
+
+// Escape sequences of arrow keys come in one read
+fn parse_key(buf: &[u8]) -> Option<Key> {
+    match buf {
+        [27] => Some(Key::Esc),
+        [27, b'[', b'A', ..] | [b'k'] => Some(Key::Up),
+        [27, b'[', b'B', ..] | [b'j'] => Some(Key::Down),
+        [b'\r'] | [b'\n'] => Some(Key::Enter),
+        [3] => Some(Key::Char('q')), // Ctrl-C
+        [c, ..] if c.is_ascii_graphic() || *c == b' ' => Some(Key::Char(*c as char)),
+        _ => None,
+    }
+}
+
+fn read_keys() -> Receiver<Key> {
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let mut buf = [0; 16];
+
+        while let Ok(n) = std::io::stdin().read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            if let Some(key) = parse_key(&buf[..n]) {
+                if tx.send(key).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    rx
+}
+
+const HELP: &str =
+    "q:quit  </>:sort  f:hide zeros  space:pause  up/down:move  enter:select  esc:back";
+
+//
+// Full-screen view of the latest interval: threads, wakeups and slices.
+// Rows of the thread table can be picked to see the details of the thread
+// (as with --tid) instead of the wakeup and slice tables of the process.
+//
+pub struct Ui {
+    _term: Terminal,
+    keys: Receiver<Key>,
+    pub paused: bool,
+    cursor: usize,
+    pids: Vec<i32>, // of the thread table rows as drawn
+    selected: Option<i32>,
+}
+
+impl Ui {
+    pub fn new(selected: Option<i32>) -> Result<Ui> {
+        Ok(Ui {
+            _term: Terminal::new()?,
+            keys: read_keys(),
+            paused: false,
+            cursor: 0,
+            pids: vec![],
+            selected,
+        })
+    }
+
+    // Waits for a key until the deadline, None once it's time to refresh
+    pub fn next_key(&self, deadline: Instant) -> Option<Key> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+
+        self.keys.recv_timeout(timeout).ok()
+    }
+
+    // Applies a key to the view, false if it's time to quit
+    pub fn handle_key(&mut self, key: Key, view: &mut View) -> bool {
+        let table = &mut view.table;
+        let columns = table.columns.len();
+
+        match key {
+            Key::Char('q') => return false,
+            Key::Char(' ') | Key::Char('p') => self.paused = !self.paused,
+            Key::Char('<') => {
+                table.sort_by = Some(table.sort_by.map_or(0, |i| (i + columns - 1) % columns));
+            }
+            Key::Char('>') => {
+                table.sort_by = Some(table.sort_by.map_or(0, |i| (i + 1) % columns));
+            }
+            Key::Char('f') => {
+                table.filter_by = match table.filter_by {
+                    Some(_) => None,
+                    None => table.sort_by,
+                };
+            }
+            Key::Up => self.cursor = self.cursor.saturating_sub(1),
+            Key::Down => self.cursor += 1,
+            Key::Enter => self.selected = self.pids.get(self.cursor).cloned(),
+            Key::Esc => self.selected = None,
+            Key::Char(_) => (),
+        }
+
+        true
+    }
+
+    fn status(&self, view: &View, rec: &Record) -> String {
+        let column = |i: Option<usize>| match i {
+            Some(i) => view.table.columns[i].title.clone(),
+            None => "-".to_string(),
+        };
+
+        format!(
+            "{}  sort: {}  hide zeros: {}{}",
+            rec.time,
+            column(view.table.sort_by),
+            column(view.table.filter_by),
+            if self.paused { "  [paused]" } else { "" }
+        )
+    }
+
+    // Same as --tid, one section after another
+    fn thread_details(&self, tid: i32, prev: &ProcessDataSnapshot, rec: &Record) -> String {
+        let mut output = format!("thread {}\n", focus::title(tid, &rec.snapshot));
+
+        for (name, mut table) in focus::sections(tid, prev, rec) {
+            output.push_str(&format!("{}\n{}\n", name, table.display_table()));
+        }
+
+        output
+    }
+
+    pub fn draw(&mut self, view: &mut View, prev: &ProcessDataSnapshot, rec: &Record) {
+        let (height, width) = Terminal::size();
+        let curr = &rec.snapshot;
+        let mut lines = vec![self.status(view, rec), HELP.to_string(), String::new()];
+
+        // the thread table gets up to a half of the screen, titles and
+        // totals included
+        let top = view.table.top;
+        let room = (height / 2).saturating_sub(4).max(1);
+        view.table.top = Some(top.map_or(room, |top| top.min(room)));
+
+        let (died, born) = delta_procs(view, prev, curr, rec.load);
+        let threads = view.table.display_table();
+
+        // grouped rows aren't threads, there is nothing to select
+        self.pids = match view.group_by {
+            Some(_) => vec![],
+            None => view
+                .table
+                .rows()
+                .iter()
+                .map(|row| match row[0] {
+                    crate::output::Data::Int(pid) => pid as i32,
+                    _ => 0,
+                })
+                .collect(),
+        };
+        self.cursor = self.cursor.min(self.pids.len().saturating_sub(1));
+        view.table.clear_data();
+        view.table.top = top;
+
+        lines.push(format!(
+            "{} threads, {} died, {} born",
+            curr.threads.len(),
+            died,
+            born
+        ));
+        // after the titles and the separator
+        let highlight = if self.pids.is_empty() {
+            None
+        } else {
+            Some(lines.len() + 2 + self.cursor)
+        };
+        lines.extend(threads.lines().map(|l| l.to_string()));
+        lines.push(String::new());
+
+        let details = match self.selected {
+            Some(tid) => self.thread_details(tid, prev, rec),
+            None => {
+                let mut tables = wakeup_tables(&rec.data.wakeups, curr);
+                format_2tables(
+                    "top inputs",
+                    &tables.inputs.display_table(),
+                    "top outputs",
+                    &tables.outputs.display_table(),
+                ) + &format_2tables(
+                    "top wakees",
+                    &tables.wakees.display_table(),
+                    "top wakers",
+                    &tables.wakers.display_table(),
+                ) + &slices_table(&rec.data.slices, curr, &view.group_by).display_table()
+            }
+        };
+        lines.extend(details.lines().map(|l| l.to_string()));
+
+        let mut screen = String::from("\x1b[H");
+        for (i, line) in lines.iter().take(height).enumerate() {
+            let line: String = line.chars().take(width).collect();
+            if Some(i) == highlight {
+                screen.push_str(&format!("\x1b[7m{}\x1b[0m", line));
+            } else {
+                screen.push_str(&line);
+            }
+            screen.push_str("\x1b[K\n");
+        }
+        screen.pop();
+        screen.push_str("\x1b[J");
+
+        print!("{}", screen);
+        let _ = std::io::stdout().flush();
+    }
+}
+
+#[test]
+fn parse_keys() {
+    assert!(matches!(parse_key(b"\x1b[A"), Some(Key::Up)));
+    assert!(matches!(parse_key(b"\x1b"), Some(Key::Esc)));
+    assert!(matches!(parse_key(b"\r"), Some(Key::Enter)));
+    assert!(matches!(parse_key(b"\x03"), Some(Key::Char('q'))));
+    assert!(matches!(parse_key(b">"), Some(Key::Char('>'))));
+    assert!(parse_key(b"\x1b[5~").is_none());
+}